@@ -0,0 +1,92 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::config::BackoffConfig;
+
+/// Exponential backoff with jitter. Each delay is drawn from the upper half of an exponentially
+/// growing window, capped at the configured maximum, so that several ingestors retrying against
+/// the same server don't all come back at once.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(config: &BackoffConfig) -> Self {
+        Self {
+            initial: Duration::from_millis(config.initial_millis),
+            max: Duration::from_millis(config.max_millis),
+            attempt: 0,
+        }
+    }
+
+    /// Number of delays handed out since creation or the last reset
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// The delay to wait before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let window = self
+            .initial
+            .saturating_mul(2_u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        let half = window / 2;
+        half + rand::rng().random_range(Duration::ZERO..=window - half)
+    }
+
+    /// Start again from the initial delay, e.g. after a successful attempt
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff(initial_millis: u64, max_millis: u64) -> Backoff {
+        Backoff::new(&BackoffConfig {
+            initial_millis,
+            max_millis,
+        })
+    }
+
+    #[test]
+    fn test_delay_grows_exponentially() {
+        let mut backoff = backoff(100, 100_000);
+        for attempt in 0..5 {
+            let window = Duration::from_millis(100 * 2_u64.pow(attempt));
+            let delay = backoff.next_delay();
+            assert!(
+                delay >= window / 2 && delay <= window,
+                "{delay:?} vs {window:?}"
+            );
+        }
+        assert_eq!(backoff.attempt(), 5);
+    }
+
+    #[test]
+    fn test_delay_capped() {
+        let mut backoff = backoff(100, 1000);
+        for _ in 0..100 {
+            assert!(backoff.next_delay() <= Duration::from_millis(1000));
+        }
+        assert!(backoff.next_delay() >= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut backoff = backoff(10, 1000);
+        for _ in 0..10 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        assert!(backoff.next_delay() <= Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,508 @@
+use std::{error::Error, fmt};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use serde::{
+    Deserialize, Deserializer,
+    de::{MapAccess, SeqAccess, Visitor},
+};
+use serde_json::Value;
+
+/// Key of the envelope BEC wraps anything msgpack can't represent directly in
+const CODEC_KEY: &str = "__bec_codec__";
+
+/// A msgpack value as it was sent, keeping binary data (e.g. numpy arrays) which JSON can't hold
+#[derive(Debug, Clone, PartialEq)]
+enum Raw {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Str(String),
+    Bin(Vec<u8>),
+    Array(Vec<Raw>),
+    Map(Vec<(Raw, Raw)>),
+}
+
+struct RawVisitor;
+
+impl<'de> Visitor<'de> for RawVisitor {
+    type Value = Raw;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any msgpack value")
+    }
+
+    fn visit_unit<E>(self) -> Result<Raw, E> {
+        Ok(Raw::Nil)
+    }
+    fn visit_none<E>(self) -> Result<Raw, E> {
+        Ok(Raw::Nil)
+    }
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Raw, D::Error> {
+        Raw::deserialize(deserializer)
+    }
+    fn visit_bool<E>(self, v: bool) -> Result<Raw, E> {
+        Ok(Raw::Bool(v))
+    }
+    fn visit_i64<E>(self, v: i64) -> Result<Raw, E> {
+        Ok(Raw::Int(v))
+    }
+    fn visit_u64<E>(self, v: u64) -> Result<Raw, E> {
+        Ok(Raw::UInt(v))
+    }
+    fn visit_f64<E>(self, v: f64) -> Result<Raw, E> {
+        Ok(Raw::Float(v))
+    }
+    fn visit_str<E>(self, v: &str) -> Result<Raw, E> {
+        Ok(Raw::Str(v.to_owned()))
+    }
+    fn visit_string<E>(self, v: String) -> Result<Raw, E> {
+        Ok(Raw::Str(v))
+    }
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Raw, E> {
+        Ok(Raw::Bin(v.to_vec()))
+    }
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Raw, E> {
+        Ok(Raw::Bin(v))
+    }
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Raw, A::Error> {
+        let mut items = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(item) = seq.next_element()? {
+            items.push(item);
+        }
+        Ok(Raw::Array(items))
+    }
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Raw, A::Error> {
+        let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some(entry) = map.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(Raw::Map(entries))
+    }
+}
+
+impl<'de> Deserialize<'de> for Raw {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(RawVisitor)
+    }
+}
+
+impl Raw {
+    fn get(&self, key: &str) -> Option<&Raw> {
+        let Raw::Map(entries) = self else {
+            return None;
+        };
+        entries
+            .iter()
+            .find(|(k, _)| matches!(k, Raw::Str(k) if k == key))
+            .map(|(_, v)| v)
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Raw::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The codec envelope, if this is a single-entry map holding one
+    fn codec(&self) -> Option<&Raw> {
+        match self {
+            Raw::Map(entries) if entries.len() == 1 => self.get(CODEC_KEY),
+            _ => None,
+        }
+    }
+}
+
+/// What a value was encoded with, going by the codec's name with or without its `Encoder` suffix
+#[derive(Debug, PartialEq)]
+enum Codec {
+    Message,
+    Numpy,
+    Enum,
+    Set,
+    Type,
+    Device,
+    Pydantic,
+    Unknown,
+}
+
+impl Codec {
+    fn from_name(encoder_name: &str) -> Codec {
+        let name = encoder_name.to_lowercase();
+        match name.strip_suffix("encoder").unwrap_or(&name) {
+            "becmessage" => Codec::Message,
+            "numpy" | "numpylist" => Codec::Numpy,
+            "enum" => Codec::Enum,
+            "set" => Codec::Set,
+            "bectype" | "type" => Codec::Type,
+            "becdevice" | "device" => Codec::Device,
+            "pydantic" | "endpointinfo" => Codec::Pydantic,
+            _ => Codec::Unknown,
+        }
+    }
+}
+
+/// The contents of a codec envelope
+struct Envelope<'a> {
+    encoder_name: &'a str,
+    type_name: &'a str,
+    data: &'a Raw,
+}
+
+impl<'a> Envelope<'a> {
+    fn parse(codec: &'a Raw) -> Result<Self, Box<dyn Error>> {
+        let field = |key| {
+            codec
+                .get(key)
+                .ok_or_else(|| format!("BEC codec value without {key}"))
+        };
+        let encoder_name = field("encoder_name")?.as_str().unwrap_or_default();
+        let type_name = field("type_name")?.as_str().unwrap_or_default();
+        Ok(Self {
+            encoder_name,
+            type_name,
+            data: field("data")?,
+        })
+    }
+
+    fn codec(&self) -> Codec {
+        Codec::from_name(self.encoder_name)
+    }
+}
+
+/// Decode the data of a codec value into what it stands for, as far as JSON can
+fn decode_codec(envelope: &Envelope) -> Result<Value, Box<dyn Error>> {
+    Ok(match envelope.codec() {
+        Codec::Numpy => numpy_array(envelope.data)?,
+        // These are encoded as their value, a list of their items, their name, the dotted name of
+        // the device or a dict of their fields, all of which are indexed as they are
+        Codec::Message
+        | Codec::Enum
+        | Codec::Set
+        | Codec::Type
+        | Codec::Device
+        | Codec::Pydantic => to_json(envelope.data),
+        Codec::Unknown => {
+            return Err(format!(
+                "Unknown BEC codec {} for type {}",
+                envelope.encoder_name, envelope.type_name
+            )
+            .into());
+        }
+    })
+}
+
+/// Convert a msgpack value to JSON, decoding any codec values nested in it. Codec values which
+/// can't be decoded are kept as they are, so that one odd value in e.g. `extra` doesn't lose the
+/// whole message.
+fn to_json(raw: &Raw) -> Value {
+    if let Some(codec) = raw.codec() {
+        let decoded = Envelope::parse(codec).and_then(|envelope| decode_codec(&envelope));
+        if let Ok(value) = decoded {
+            return value;
+        }
+        return serde_json::json!({ CODEC_KEY: to_json_plain(codec) });
+    }
+    match raw {
+        Raw::Array(items) => items.iter().map(to_json).collect(),
+        Raw::Map(entries) => entries
+            .iter()
+            .map(|(k, v)| (key_string(k), to_json(v)))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        _ => scalar(raw),
+    }
+}
+
+/// Convert a msgpack value to JSON without decoding codec values
+fn to_json_plain(raw: &Raw) -> Value {
+    match raw {
+        Raw::Array(items) => items.iter().map(to_json_plain).collect(),
+        Raw::Map(entries) => entries
+            .iter()
+            .map(|(k, v)| (key_string(k), to_json_plain(v)))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        _ => scalar(raw),
+    }
+}
+
+fn scalar(raw: &Raw) -> Value {
+    match raw {
+        Raw::Nil => Value::Null,
+        Raw::Bool(b) => (*b).into(),
+        Raw::Int(i) => (*i).into(),
+        Raw::UInt(u) => (*u).into(),
+        // NaN and infinities have no JSON representation
+        Raw::Float(f) => serde_json::Number::from_f64(*f).map_or(Value::Null, Value::Number),
+        Raw::Str(s) => s.clone().into(),
+        Raw::Bin(bytes) => BASE64.encode(bytes).into(),
+        Raw::Array(_) | Raw::Map(_) => to_json_plain(raw),
+    }
+}
+
+fn key_string(key: &Raw) -> String {
+    match key {
+        Raw::Str(s) => s.clone(),
+        other => to_json_plain(other).to_string(),
+    }
+}
+
+/// Numbers from the raw bytes of a numpy array with the given dtype, e.g. `<f8`, or None if it
+/// isn't a plain numeric type
+fn numpy_values(dtype: &str, bytes: &[u8]) -> Option<Vec<Value>> {
+    let mut chars = dtype.chars();
+    let big_endian = match chars.next()? {
+        '>' => true,
+        '<' | '|' | '=' => false,
+        _ => return None,
+    };
+    let kind = chars.next()?;
+    let size: usize = chars.as_str().parse().ok()?;
+    let supported = matches!(
+        (kind, size),
+        ('f', 4 | 8) | ('i' | 'u', 1 | 2 | 4 | 8) | ('b', 1)
+    );
+    if !supported || !bytes.len().is_multiple_of(size) {
+        return None;
+    }
+    let values = bytes.chunks_exact(size).map(|chunk| {
+        let mut buf = [0u8; 8];
+        if big_endian {
+            buf[8 - size..].copy_from_slice(chunk);
+            buf.reverse();
+        } else {
+            buf[..size].copy_from_slice(chunk);
+        }
+        let raw = u64::from_le_bytes(buf);
+        let signed = |bits: usize| ((raw << (64 - bits)) as i64) >> (64 - bits);
+        match (kind, size) {
+            ('f', 4) => scalar(&Raw::Float(f32::from_bits(raw as u32).into())),
+            ('f', _) => scalar(&Raw::Float(f64::from_bits(raw))),
+            ('i', _) => signed(size * 8).into(),
+            ('u', _) => raw.into(),
+            _ => (raw != 0).into(),
+        }
+    });
+    Some(values.collect())
+}
+
+/// Nest a flat list of values into the given shape
+fn reshape(values: &[Value], shape: &[usize]) -> Value {
+    match shape {
+        [] => values.first().cloned().unwrap_or_default(),
+        [_] => values.to_vec().into(),
+        [_, inner @ ..] => {
+            let chunk = inner.iter().product::<usize>().max(1);
+            values
+                .chunks(chunk)
+                .map(|chunk| reshape(chunk, inner))
+                .collect()
+        }
+    }
+}
+
+/// A numpy array, encoded by BEC as its dtype, shape and raw bytes. Numeric arrays become nested
+/// lists; anything else is kept as the encoded bytes along with its dtype and shape.
+fn numpy_array(data: &Raw) -> Result<Value, Box<dyn Error>> {
+    let dtype = data
+        .get("type")
+        .or_else(|| data.get("dtype"))
+        .and_then(Raw::as_str)
+        .unwrap_or_default();
+    let shape: Vec<usize> = match data.get("shape") {
+        Some(Raw::Array(dims)) => dims
+            .iter()
+            .filter_map(|d| match d {
+                Raw::UInt(n) => usize::try_from(*n).ok(),
+                Raw::Int(n) => usize::try_from(*n).ok(),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    };
+    let bytes = match data.get("data") {
+        Some(Raw::Bin(bytes)) => bytes.as_slice(),
+        _ => &[],
+    };
+    // Multiplying from the innermost dimension checks every chunk size `reshape` uses
+    let len = shape
+        .iter()
+        .rev()
+        .try_fold(1usize, |len, &dim| len.checked_mul(dim))
+        .ok_or_else(|| format!("Numpy array shape {shape:?} is too large"))?;
+    Ok(match numpy_values(dtype, bytes) {
+        Some(values) if values.len() == len => reshape(&values, &shape),
+        _ => serde_json::json!({
+            "dtype": dtype,
+            "shape": shape,
+            "data": BASE64.encode(bytes),
+        }),
+    })
+}
+
+/// A BEC message, e.g. a `LogMessage` or `ScanStatusMessage`, decoded from its codec envelope
+#[derive(Debug, PartialEq)]
+pub struct BecMessage {
+    pub type_name: String,
+    pub data: Value,
+}
+
+/// Decode a msgpacked BEC message. Anything other than a BEC message, including codec values of
+/// unknown types, is an error.
+pub fn decode_message(bytes: &[u8]) -> Result<BecMessage, Box<dyn Error>> {
+    let raw: Raw = rmp_serde::from_slice(bytes)?;
+    let codec = raw
+        .codec()
+        .ok_or("Not a BEC message: no __bec_codec__ envelope")?;
+    let envelope = Envelope::parse(codec)?;
+    match envelope.codec() {
+        Codec::Message => Ok(BecMessage {
+            type_name: envelope.type_name.to_owned(),
+            data: to_json(envelope.data),
+        }),
+        Codec::Unknown => Err(format!(
+            "Unknown BEC codec {} for type {}",
+            envelope.encoder_name, envelope.type_name
+        )
+        .into()),
+        _ => Err(format!(
+            "Not a BEC message: a {} encoded with {}",
+            envelope.type_name, envelope.encoder_name
+        )
+        .into()),
+    }
+}
+
+/// Decode any msgpacked value, decoding the BEC codec values in it
+pub fn decode_value(bytes: &[u8]) -> Result<Value, Box<dyn Error>> {
+    let raw: Raw = rmp_serde::from_slice(bytes)?;
+    Ok(to_json(&raw))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Serialize;
+
+    /// A codec envelope as BEC would send it
+    fn envelope(encoder_name: &str, type_name: &str, data: impl Serialize) -> serde_json::Value {
+        serde_json::json!({ CODEC_KEY: {
+            "encoder_name": encoder_name,
+            "type_name": type_name,
+            "data": serde_json::to_value(data).unwrap(),
+        }})
+    }
+
+    fn pack(value: &impl Serialize) -> Vec<u8> {
+        rmp_serde::to_vec_named(value).unwrap()
+    }
+
+    /// Msgpack for a numpy array, which needs binary data that serde_json can't hold
+    fn numpy_msgpack(dtype: &str, shape: &[u64], data: &[u8]) -> Vec<u8> {
+        let mut buf = vec![];
+        rmp::encode::write_map_len(&mut buf, 1).unwrap();
+        rmp::encode::write_str(&mut buf, CODEC_KEY).unwrap();
+        rmp::encode::write_map_len(&mut buf, 3).unwrap();
+        rmp::encode::write_str(&mut buf, "encoder_name").unwrap();
+        rmp::encode::write_str(&mut buf, "NumpyEncoder").unwrap();
+        rmp::encode::write_str(&mut buf, "type_name").unwrap();
+        rmp::encode::write_str(&mut buf, "ndarray").unwrap();
+        rmp::encode::write_str(&mut buf, "data").unwrap();
+        rmp::encode::write_map_len(&mut buf, 3).unwrap();
+        rmp::encode::write_str(&mut buf, "type").unwrap();
+        rmp::encode::write_str(&mut buf, dtype).unwrap();
+        rmp::encode::write_str(&mut buf, "shape").unwrap();
+        rmp::encode::write_array_len(&mut buf, shape.len() as u32).unwrap();
+        for dim in shape {
+            rmp::encode::write_uint(&mut buf, *dim).unwrap();
+        }
+        rmp::encode::write_str(&mut buf, "data").unwrap();
+        rmp::encode::write_bin(&mut buf, data).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_decode_message() {
+        let message = envelope(
+            "BECMessageEncoder",
+            "ScanStatusMessage",
+            serde_json::json!({
+                "scan_id": "abc",
+                "status": envelope("EnumEncoder", "ScanStatus", "open"),
+                "info": { "positions": envelope("SetEncoder", "set", [1, 2]) },
+            }),
+        );
+        let decoded = decode_message(&pack(&message)).unwrap();
+        assert_eq!(decoded.type_name, "ScanStatusMessage");
+        assert_eq!(
+            decoded.data,
+            serde_json::json!({
+                "scan_id": "abc",
+                "status": "open",
+                "info": { "positions": [1, 2] },
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_message_errors() {
+        let unknown = envelope("MysteryEncoder", "Thing", 1);
+        let error = decode_message(&pack(&unknown)).unwrap_err().to_string();
+        assert_eq!(error, "Unknown BEC codec MysteryEncoder for type Thing");
+
+        let not_a_message = envelope("EnumEncoder", "ScanStatus", "open");
+        assert!(decode_message(&pack(&not_a_message)).is_err());
+        assert!(decode_message(&pack(&serde_json::json!({ "a": 1 }))).is_err());
+        assert!(decode_message(&[0xc1]).is_err());
+    }
+
+    #[test]
+    fn test_unknown_nested_codec_kept() {
+        let value = serde_json::json!({ "x": envelope("MysteryEncoder", "Thing", 1) });
+        assert_eq!(decode_value(&pack(&value)).unwrap(), value);
+    }
+
+    #[test]
+    fn test_numpy_array() {
+        let data: Vec<u8> = [1.5f64, -2.0, 3.25, 4.0]
+            .iter()
+            .flat_map(|f| f.to_le_bytes())
+            .collect();
+        let value = decode_value(&numpy_msgpack("<f8", &[2, 2], &data)).unwrap();
+        assert_eq!(value, serde_json::json!([[1.5, -2.0], [3.25, 4.0]]));
+
+        let data: Vec<u8> = [-1i32, 7].iter().flat_map(|i| i.to_be_bytes()).collect();
+        let value = decode_value(&numpy_msgpack(">i4", &[2], &data)).unwrap();
+        assert_eq!(value, serde_json::json!([-1, 7]));
+
+        let value = decode_value(&numpy_msgpack("<U3", &[1], b"abc\0\0\0\0\0\0\0\0\0")).unwrap();
+        assert_eq!(value["dtype"], "<U3");
+        assert_eq!(value["shape"], serde_json::json!([1]));
+
+        // Shapes too large to count are a decode error, so the array is kept as it was encoded
+        let decode = |shape: &[u64]| {
+            let raw: Raw = rmp_serde::from_slice(&numpy_msgpack("<f8", shape, &[])).unwrap();
+            decode_codec(&Envelope::parse(raw.codec().unwrap()).unwrap()).map_err(|e| e.to_string())
+        };
+        let huge = u64::MAX / 2;
+        assert!(decode(&[huge, huge]).unwrap_err().contains("too large"));
+        assert!(decode(&[0, huge, huge]).is_err());
+        let value = decode_value(&numpy_msgpack("<f8", &[huge, huge], &[])).unwrap();
+        assert_eq!(value[CODEC_KEY]["type_name"], "ndarray");
+        assert_eq!(
+            decode_value(&numpy_msgpack("<f8", &[0, 3], &[])).unwrap(),
+            serde_json::json!([])
+        );
+    }
+
+    #[test]
+    fn test_codec_names() {
+        assert_eq!(Codec::from_name("BECMessageEncoder"), Codec::Message);
+        assert_eq!(Codec::from_name("BECMessage"), Codec::Message);
+        assert_eq!(Codec::from_name("NumpyEncoder"), Codec::Numpy);
+        assert_eq!(Codec::from_name("other"), Codec::Unknown);
+    }
+}
@@ -0,0 +1,373 @@
+use std::error::Error;
+
+use elasticsearch::http::Method;
+
+use crate::{
+    backoff::Backoff,
+    config::{BootstrapConfig, ElasticConfig, Profile},
+    index_name,
+    nodes::NodePool,
+};
+
+/// Bump this whenever the policy or template below change, so that existing installs get updated
+const BOOTSTRAP_VERSION: u64 = 3;
+const MANAGED_BY: &str = "bec_log_ingestor";
+
+/// Recorded in the `_meta` of everything we install, to tell whether it is ours and up to date
+fn meta(config: &BootstrapConfig) -> serde_json::Value {
+    let mut meta = serde_json::json!({
+        "managed_by": MANAGED_BY,
+        "version": BOOTSTRAP_VERSION,
+        "rollover_max_age": config.rollover_max_age,
+        "rollover_max_primary_shard_size": config.rollover_max_primary_shard_size,
+    });
+    // Elastic doesn't keep nulls, which would make it look out of date on every startup
+    if let Some(retention) = &config.retention {
+        meta["retention"] = retention.clone().into();
+    }
+    meta
+}
+
+fn ilm_policy(config: &BootstrapConfig) -> serde_json::Value {
+    let mut phases = serde_json::json!({
+        "hot": {
+            "actions": {
+                "rollover": {
+                    "max_age": config.rollover_max_age,
+                    "max_primary_shard_size": config.rollover_max_primary_shard_size,
+                }
+            }
+        }
+    });
+    if let Some(retention) = &config.retention {
+        phases["delete"] = serde_json::json!({
+            "min_age": retention,
+            "actions": { "delete": {} }
+        });
+    }
+    serde_json::json!({ "policy": { "_meta": meta(config), "phases": phases } })
+}
+
+/// Mappings for the fields of the documents made from log messages with a profile's fields.
+/// Anything else, e.g. from streams which aren't BEC logs, is mapped dynamically.
+fn log_mappings(profile: Profile) -> serde_json::Value {
+    match profile {
+        Profile::Default => default_mappings(),
+        Profile::Ecs => ecs_mappings(),
+    }
+}
+
+fn default_mappings() -> serde_json::Value {
+    serde_json::json!({
+        "properties": {
+            "@timestamp": { "type": "date_nanos" },
+            "event": { "properties": { "ingested": { "type": "date_nanos" } } },
+            "timezone": { "type": "keyword" },
+            "file": {
+                "properties": {
+                    "name": { "type": "keyword" },
+                    "path": { "type": "keyword" },
+                }
+            },
+            "function": { "type": "keyword" },
+            "message": { "type": "text" },
+            "log_type": { "type": "keyword" },
+            "line": { "type": "integer" },
+            "module": { "type": "keyword" },
+            "service_name": { "type": "keyword" },
+            "proc_id": { "type": "long" },
+            "schema_version": { "type": "short" },
+            // Whatever newer BEC releases add, in any shape
+            "unknown_fields": { "type": "flattened" },
+            // Exceptions come in whatever shape BEC serialised them, so don't let them conflict.
+            // Formatted tracebacks are turned into objects to fit, see `mapping::exception_object`.
+            "exception": { "type": "flattened" },
+            "redis_stream": { "type": "keyword" },
+        }
+    })
+}
+
+/// Mappings for the ECS fields of `Profile::Ecs`, with the types the schema gives them
+fn ecs_mappings() -> serde_json::Value {
+    serde_json::json!({
+        "properties": {
+            "@timestamp": { "type": "date_nanos" },
+            "ecs": { "properties": { "version": { "type": "keyword" } } },
+            "event": {
+                "properties": {
+                    "duration": { "type": "long" },
+                    "ingested": { "type": "date_nanos" },
+                    "timezone": { "type": "keyword" },
+                    "original": { "type": "keyword", "index": false, "doc_values": false },
+                }
+            },
+            "error": {
+                "properties": {
+                    "message": { "type": "match_only_text" },
+                    "stack_trace": { "type": "wildcard" },
+                    "type": { "type": "keyword" },
+                }
+            },
+            "log": {
+                "properties": {
+                    "level": { "type": "keyword" },
+                    "logger": { "type": "keyword" },
+                    "origin": {
+                        "properties": {
+                            "file": {
+                                "properties": {
+                                    "line": { "type": "long" },
+                                    "name": { "type": "keyword" },
+                                    "path": { "type": "keyword" },
+                                }
+                            },
+                            "function": { "type": "keyword" },
+                        }
+                    },
+                }
+            },
+            "message": { "type": "match_only_text" },
+            "process": {
+                "properties": {
+                    "name": { "type": "keyword" },
+                    "pid": { "type": "long" },
+                    "thread": {
+                        "properties": {
+                            "id": { "type": "long" },
+                            "name": { "type": "keyword" },
+                        }
+                    },
+                }
+            },
+            "service": { "properties": { "name": { "type": "keyword" } } },
+            "bec": {
+                "properties": {
+                    "elapsed": { "type": "keyword" },
+                    "extra": { "type": "flattened" },
+                    "level_icon": { "type": "keyword" },
+                    "level_no": { "type": "integer" },
+                    "module": { "type": "keyword" },
+                    "schema_version": { "type": "short" },
+                    "unknown_fields": { "type": "flattened" },
+                }
+            },
+            "redis_stream": { "type": "keyword" },
+        }
+    })
+}
+
+/// An index template for the data stream, or for every data stream a templated index can produce
+fn index_template(
+    config: &BootstrapConfig,
+    data_stream: &str,
+    profile: Profile,
+) -> serde_json::Value {
+    let mut meta = meta(config);
+    meta["data_stream"] = data_stream.into();
+    meta["policy"] = config.policy_name.clone().into();
+    meta["profile"] = serde_json::to_value(profile).unwrap_or_default();
+    serde_json::json!({
+        "index_patterns": [index_name::pattern(data_stream)],
+        "data_stream": {},
+        // Above the built-in templates for logs-*-* etc.
+        "priority": 200,
+        "version": BOOTSTRAP_VERSION,
+        "_meta": meta,
+        "template": {
+            "settings": { "index.lifecycle.name": config.policy_name },
+            "mappings": log_mappings(profile),
+        }
+    })
+}
+
+/// Whether to install something, given the `_meta` of the existing one (None if there is none).
+/// Things we didn't install, or which a newer version of the ingestor installed, are left alone.
+fn should_install(
+    existing: Option<&serde_json::Value>,
+    desired: &serde_json::Value,
+    what: &str,
+) -> bool {
+    let Some(existing) = existing else {
+        return true;
+    };
+    if existing["managed_by"] != MANAGED_BY {
+        println!(
+            "Elastic bootstrap: {what} exists but wasn't installed by the ingestor, leaving it"
+        );
+        return false;
+    }
+    if existing["version"].as_u64() > desired["version"].as_u64() {
+        println!("Elastic bootstrap: {what} was installed by a newer ingestor, leaving it");
+        return false;
+    }
+    existing != desired
+}
+
+/// Get the `_meta` at `pointer` in the response for an existing resource, or None if it doesn't exist
+async fn existing_meta(
+    nodes: &NodePool,
+    path: &str,
+    pointer: &str,
+) -> Result<Option<serde_json::Value>, Box<dyn Error>> {
+    match nodes.send(Method::Get, path, None).await? {
+        (404, _) => Ok(None),
+        (status, body) if (200..300).contains(&status) => {
+            Ok(Some(body.pointer(pointer).cloned().unwrap_or_default()))
+        }
+        (status, body) => Err(format!("GET {path} failed with {status}: {body}").into()),
+    }
+}
+
+async fn put(nodes: &NodePool, path: &str, body: serde_json::Value) -> Result<(), Box<dyn Error>> {
+    match nodes.send(Method::Put, path, Some(body)).await? {
+        (status, _) if (200..300).contains(&status) => Ok(()),
+        (status, body) => Err(format!("PUT {path} failed with {status}: {body}").into()),
+    }
+}
+
+async fn try_bootstrap(
+    nodes: &NodePool,
+    config: &BootstrapConfig,
+    data_stream: &str,
+    profile: Profile,
+) -> Result<(), Box<dyn Error>> {
+    let policy = ilm_policy(config);
+    let path = format!("/_ilm/policy/{}", config.policy_name);
+    let pointer = format!("/{}/policy/_meta", config.policy_name);
+    let existing = existing_meta(nodes, &path, &pointer).await?;
+    let what = format!("ILM policy {}", config.policy_name);
+    if should_install(existing.as_ref(), &policy["policy"]["_meta"], &what) {
+        put(nodes, &path, policy).await?;
+        println!("Elastic bootstrap: installed {what}");
+    }
+
+    let template = index_template(config, data_stream, profile);
+    let path = format!("/_index_template/{}", config.template_name);
+    let existing = existing_meta(nodes, &path, "/index_templates/0/index_template/_meta").await?;
+    let what = format!("index template {}", config.template_name);
+    if should_install(existing.as_ref(), &template["_meta"], &what) {
+        put(nodes, &path, template).await?;
+        println!("Elastic bootstrap: installed {what}");
+    }
+
+    // Data streams for a templated index are created by Elastic as documents first arrive for them
+    if index_name::is_template(data_stream) {
+        return Ok(());
+    }
+    let path = format!("/_data_stream/{data_stream}");
+    if existing_meta(nodes, &path, "").await?.is_none() {
+        put(nodes, &path, serde_json::json!({}))
+            .await
+            .map_err(|e| {
+                format!(
+                    "Can't create data stream {data_stream}, is there an index by that name? {e}"
+                )
+            })?;
+        println!("Elastic bootstrap: created data stream {data_stream}");
+    }
+    Ok(())
+}
+
+/// Install the ILM policy and index template for the configured index, and create it as a data
+/// stream. Retries while Elastic can't be reached, since writing before the template is in place
+/// would leave the index with dynamic mappings.
+pub async fn bootstrap(nodes: &NodePool, config: &ElasticConfig) -> Result<(), Box<dyn Error>> {
+    let Some(bootstrap) = &config.bootstrap else {
+        return Ok(());
+    };
+    let mut backoff = Backoff::new(&config.transport_retry.backoff);
+    loop {
+        let profile = config.mapping.profile;
+        match try_bootstrap(nodes, bootstrap, &config.index, profile).await {
+            Ok(()) => return Ok(()),
+            Err(error) if error.downcast_ref::<elasticsearch::Error>().is_some() => {
+                let delay = backoff.next_delay();
+                println!("Elastic bootstrap failed: {error}, retrying in {delay:?}");
+                tokio::time::sleep(delay).await;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(retention: Option<&str>) -> BootstrapConfig {
+        let retention = retention
+            .map(|r| format!("retention = \"{r}\""))
+            .unwrap_or_default();
+        toml::from_str(&retention).unwrap()
+    }
+
+    #[test]
+    fn test_ilm_policy() {
+        let policy = ilm_policy(&config(None));
+        let phases = &policy["policy"]["phases"];
+        assert_eq!(phases["hot"]["actions"]["rollover"]["max_age"], "1d");
+        assert_eq!(
+            phases["hot"]["actions"]["rollover"]["max_primary_shard_size"],
+            "50gb"
+        );
+        assert!(phases.get("delete").is_none());
+
+        let policy = ilm_policy(&config(Some("30d")));
+        assert_eq!(policy["policy"]["phases"]["delete"]["min_age"], "30d");
+        assert_eq!(policy["policy"]["_meta"]["retention"], "30d");
+    }
+
+    #[test]
+    fn test_index_template() {
+        let template = index_template(&config(None), "bec-logs", Profile::Default);
+        assert_eq!(template["index_patterns"], serde_json::json!(["bec-logs"]));
+        assert_eq!(template["data_stream"], serde_json::json!({}));
+        assert_eq!(
+            template["template"]["settings"]["index.lifecycle.name"],
+            "bec-log-ingestor"
+        );
+        let properties = &template["template"]["mappings"]["properties"];
+        assert_eq!(properties["line"]["type"], "integer");
+        assert_eq!(properties["exception"]["type"], "flattened");
+        assert_eq!(template["_meta"]["data_stream"], "bec-logs");
+
+        let template = index_template(
+            &config(None),
+            "bec-{service_name}-{%Y.%m.%d}",
+            Profile::Default,
+        );
+        assert_eq!(template["index_patterns"], serde_json::json!(["bec-*-*"]));
+
+        let template = index_template(&config(None), "bec-logs", Profile::Ecs);
+        let properties = &template["template"]["mappings"]["properties"];
+        assert_eq!(properties["log"]["properties"]["level"]["type"], "keyword");
+        assert_eq!(properties["process"]["properties"]["pid"]["type"], "long");
+        assert_eq!(template["_meta"]["profile"], "ecs");
+    }
+
+    #[test]
+    fn test_should_install() {
+        let desired = meta(&config(None));
+        assert!(should_install(None, &desired, "policy"));
+        assert!(!should_install(Some(&desired), &desired, "policy"));
+
+        let changed = meta(&config(Some("30d")));
+        assert!(should_install(Some(&changed), &desired, "policy"));
+
+        let mut older = desired.clone();
+        older["version"] = 0.into();
+        assert!(should_install(Some(&older), &desired, "policy"));
+
+        let mut newer = desired.clone();
+        newer["version"] = (BOOTSTRAP_VERSION + 1).into();
+        assert!(!should_install(Some(&newer), &desired, "policy"));
+
+        let foreign = serde_json::json!({ "description": "someone else's" });
+        assert!(!should_install(Some(&foreign), &desired, "policy"));
+        assert!(!should_install(
+            Some(&serde_json::Value::Null),
+            &desired,
+            "policy"
+        ));
+    }
+}
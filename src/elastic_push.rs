@@ -1,89 +1,435 @@
-use elasticsearch::{Elasticsearch, http::request::JsonBody};
+use elasticsearch::http::request::JsonBody;
 use tokio::sync::mpsc;
 
-use std::{error::Error, iter::once};
+use std::{iter::once, sync::Arc, time::Duration};
 
 use crate::{
-    config::ElasticConfig,
-    redis_logs::{LogMsg, LogRecord},
+    backoff::Backoff,
+    bootstrap::bootstrap,
+    config::{ElasticConfig, MappingConfig, TransportRetryConfig},
+    index_name, mapping,
+    nodes::{NodePool, sniff_loop},
+    redis_logs::{EntryId, InFlight, Payload, StreamMsg},
 };
 
-fn elastic_client(config: &ElasticConfig) -> Result<Elasticsearch, Box<dyn Error>> {
-    let url = elasticsearch::http::Url::parse(&config.url.full_url())?;
-    let conn_pool = elasticsearch::http::transport::SingleNodeConnectionPool::new(url);
-    let credentials = config.credentials()?;
-    let transport = elasticsearch::http::transport::TransportBuilder::new(conn_pool)
-        .auth(credentials)
-        .cert_validation(elasticsearch::cert::CertificateValidation::None)
-        .build()?;
-    Ok(Elasticsearch::new(transport))
-}
-
-/// Convert a LogRecord to the document we want Elastic to ingest
-fn json_from_logmsg(msg: &LogMsg) -> Result<serde_json::Value, serde_json::Error> {
-    // dbg!(serde_json::to_value(record))
-    dbg!(Ok(serde_json::json!({
-        "@timestamp": msg.record.time.as_rfc3339(),
-        "file": msg.record.file,
-        "function": msg.record.function,
-        "message": msg.record.message,
-        "log_type": msg.record.level.name,
-        "line": msg.record.line,
-        "module": msg.record.module,
-        "service_name": msg.service_name,
-        "proc_id": msg.record.process.id,
-        "exception": msg.record.exception,
-    })))
+/// Convert any decoded stream entry to a document, tagged with the stream it came from. Log
+/// messages are mapped as configured; other values are indexed as they are, timestamped with the
+/// time of ingestion.
+fn json_from_msg(
+    msg: &StreamMsg,
+    mapping: &MappingConfig,
+) -> Result<serde_json::Value, serde_json::Error> {
+    let mut doc = match &msg.payload {
+        Payload::Log(log) => mapping::log_document(log, mapping)?,
+        Payload::Value(serde_json::Value::Object(map)) => {
+            let mut doc = map.clone();
+            doc.entry("@timestamp")
+                .or_insert_with(|| chrono::Utc::now().to_rfc3339().into());
+            doc.into()
+        }
+        Payload::Value(value) => serde_json::json!({
+            "@timestamp": chrono::Utc::now().to_rfc3339(),
+            "data": value,
+        }),
+    };
+    doc["redis_stream"] = msg.entry.stream.clone().into();
+    mapping::finish_document(&mut doc, mapping);
+    Ok(doc)
+}
+
+/// The index a message goes to, from its stream's index or the default one, with any template
+/// filled in from the message
+fn document_index(msg: &StreamMsg, config: &ElasticConfig) -> String {
+    index_name::render(msg.index.as_deref().unwrap_or(&config.index), msg)
+}
+
+/// The document ID for a message, from the stream entry it came from. Sending the same entry
+/// again, e.g. after a timeout or once it has been claimed again, then gets a 409 rather than
+/// indexing it twice.
+fn document_id(entry: &EntryId) -> String {
+    format!("{}-{}", entry.stream, entry.id)
+}
+
+/// The bulk action for a message, which names the index unless it is the plain default one given
+/// in the request path
+fn bulk_action(msg: &StreamMsg, config: &ElasticConfig) -> serde_json::Value {
+    let id = document_id(&msg.entry);
+    if msg.index.is_none() && !index_name::is_template(&config.index) {
+        return serde_json::json!({ "create": { "_id": id } });
+    }
+    serde_json::json!({ "create": { "_index": document_index(msg, config), "_id": id } })
 }
 
 fn make_json_body(
-    msgs: &Vec<LogMsg>,
+    msgs: &[&StreamMsg],
+    config: &ElasticConfig,
 ) -> Result<Vec<JsonBody<serde_json::Value>>, serde_json::Error> {
-    let action = serde_json::json!({ "create": {} });
-
     let values = msgs
         .iter()
-        .map(|e| json_from_logmsg(e))
+        .map(|msg| json_from_msg(msg, &config.mapping))
         .collect::<Result<Vec<serde_json::Value>, serde_json::Error>>()?;
 
-    Ok(values
+    Ok(msgs
         .iter()
-        .flat_map(|e| {
-            once(JsonBody::from(action.clone())).chain(once(JsonBody::from(e.to_owned())))
+        .zip(values)
+        .flat_map(|(msg, doc)| {
+            once(JsonBody::from(bulk_action(msg, config))).chain(once(JsonBody::from(doc)))
         })
         .collect())
 }
 
-pub async fn consumer_loop(rx: &mut mpsc::UnboundedReceiver<LogMsg>, config: ElasticConfig) {
-    let elastic_client = elastic_client(&config).expect("Failed to connect to Elastic!");
+/// What became of a document sent in a bulk request
+#[derive(Debug, PartialEq)]
+enum ItemOutcome {
+    Created,
+    /// Rejected for a reason which may go away, e.g. the cluster being overloaded
+    Retryable(String),
+    /// Rejected for a reason which retrying won't fix, e.g. a mapping conflict
+    Permanent {
+        status: u64,
+        reason: String,
+    },
+}
+
+fn item_error_reason(item: &serde_json::Value) -> String {
+    let error = &item["error"];
+    match (error["type"].as_str(), error["reason"].as_str()) {
+        (Some(error_type), Some(reason)) => format!("{error_type}: {reason}"),
+        _ => error.to_string(),
+    }
+}
+
+/// Classify each document in a bulk request by the response Elastic gave for it.
+/// Items in a bulk response are in the same order as the actions in the request; if there are
+/// no items at all, the request as a whole failed and every document may be retried.
+fn item_outcomes(response: &serde_json::Value, count: usize) -> Vec<ItemOutcome> {
+    let items = response["items"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or(&[]);
+    (0..count)
+        .map(|i| {
+            let Some(item) = items.get(i).map(|item| &item["create"]) else {
+                return ItemOutcome::Retryable(format!("No result in bulk response: {response}"));
+            };
+            match item["status"].as_u64() {
+                // A conflict means the document was already written, e.g. by an earlier attempt
+                Some(200 | 201 | 409) => ItemOutcome::Created,
+                Some(429 | 502 | 503 | 504) => ItemOutcome::Retryable(item_error_reason(item)),
+                Some(status) => ItemOutcome::Permanent {
+                    status,
+                    reason: item_error_reason(item),
+                },
+                None => ItemOutcome::Retryable(format!("No status in bulk response item: {item}")),
+            }
+        })
+        .collect()
+}
+
+/// Send documents in a single bulk request to the next node in the pool, and classify the result
+/// for each of them. A templated index isn't a real one, so every action names its own instead.
+async fn bulk_send(
+    nodes: &NodePool,
+    index: &str,
+    body: Vec<JsonBody<serde_json::Value>>,
+    count: usize,
+) -> Result<Vec<ItemOutcome>, elasticsearch::Error> {
+    let (url, client) = nodes.next().await;
+    let parts = if index_name::is_template(index) {
+        elasticsearch::BulkParts::None
+    } else {
+        elasticsearch::BulkParts::Index(index)
+    };
+    let response = client
+        .bulk(parts)
+        .body(body)
+        .send()
+        .await
+        .inspect_err(|_| nodes.mark_dead(&url))?;
+    nodes.mark_alive(&url);
+    let status = response.status_code();
+    let body = response.json::<serde_json::Value>().await?;
+    if !status.is_success() {
+        println!("Bulk request to elastic failed with status {status}");
+    }
+    Ok(item_outcomes(&body, count))
+}
 
-    let mut buffer: Vec<LogMsg> = Vec::with_capacity(config.chunk_size.into());
+/// Whether to stop retrying a request which has failed `attempts` times over `elapsed`
+fn transport_retry_exhausted(
+    attempts: u32,
+    elapsed: Duration,
+    config: &TransportRetryConfig,
+) -> bool {
+    config.max_attempts.is_some_and(|max| attempts >= max)
+        || elapsed >= Duration::from_millis(config.max_elapsed_millis)
+}
 
+/// Send a bulk request, retrying it with backoff while Elastic can't be reached, e.g. while it
+/// is down or being upgraded. The body is rebuilt for every attempt since sending consumes it.
+async fn bulk_send_retrying(
+    nodes: &NodePool,
+    index: &str,
+    body: impl Fn() -> Vec<JsonBody<serde_json::Value>>,
+    count: usize,
+    config: &TransportRetryConfig,
+) -> Result<Vec<ItemOutcome>, elasticsearch::Error> {
+    let start = tokio::time::Instant::now();
+    let mut backoff = Backoff::new(&config.backoff);
     loop {
-        let open = rx.recv_many(&mut buffer, config.chunk_size.into()).await;
-        if open == 0 {
+        match bulk_send(nodes, index, body(), count).await {
+            Ok(outcomes) => return Ok(outcomes),
+            Err(error) => {
+                let attempts = backoff.attempt() + 1;
+                if transport_retry_exhausted(attempts, start.elapsed(), config) {
+                    println!(
+                        "Giving up on bulk request to {index} after {attempts} attempts over {:?}",
+                        start.elapsed()
+                    );
+                    return Err(error);
+                }
+                let delay = backoff.next_delay();
+                println!(
+                    "Bulk request to {index} failed (attempt {attempts}): {error}, retrying in {delay:?}"
+                );
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+/// A document for the dead-letter index recording why a message was rejected. The original
+/// document is kept as a string so that it can't cause another mapping conflict.
+fn dead_letter_doc(
+    msg: &StreamMsg,
+    status: u64,
+    reason: &str,
+    config: &ElasticConfig,
+) -> serde_json::Value {
+    serde_json::json!({
+        "@timestamp": chrono::Utc::now().to_rfc3339(),
+        "redis_stream": msg.entry.stream,
+        "stream_id": msg.entry.id,
+        "index": document_index(msg, config),
+        "status": status,
+        "error": reason,
+        "document": json_from_msg(msg, &config.mapping).map(|doc| doc.to_string()).unwrap_or_default(),
+    })
+}
+
+/// Write permanently rejected messages to the dead-letter index.
+/// Returns the entries which were written, and so are dealt with.
+async fn dead_letter(
+    nodes: &NodePool,
+    rejected: &[(&StreamMsg, u64, String)],
+    config: &ElasticConfig,
+) -> Vec<EntryId> {
+    let docs: Vec<serde_json::Value> = rejected
+        .iter()
+        .map(|(msg, status, reason)| dead_letter_doc(msg, *status, reason, config))
+        .collect();
+    let body = || {
+        rejected
+            .iter()
+            .zip(&docs)
+            .flat_map(|((msg, _, _), doc)| {
+                let action = serde_json::json!({ "create": { "_id": document_id(&msg.entry) } });
+                once(JsonBody::from(action)).chain(once(JsonBody::from(doc.clone())))
+            })
+            .collect()
+    };
+    let index = &config.dead_letter_index;
+    match bulk_send_retrying(nodes, index, body, rejected.len(), &config.transport_retry).await {
+        Ok(outcomes) => rejected
+            .iter()
+            .zip(outcomes)
+            .filter(|(_, outcome)| *outcome == ItemOutcome::Created)
+            .map(|((msg, _, _), _)| msg.entry.clone())
+            .collect(),
+        Err(error) => {
+            println!(
+                "Failed to write to dead-letter index {}: {error}",
+                config.dead_letter_index
+            );
+            vec![]
+        }
+    }
+}
+
+/// Send a batch of messages to Elastic, retrying documents which were rejected for retryable
+/// reasons and dead-lettering the ones which were rejected permanently.
+/// Returns the entries which are dealt with and may be acknowledged. Anything else is left
+/// pending in Redis, to be claimed and retried later.
+async fn send_batch(nodes: &NodePool, msgs: &[StreamMsg], config: &ElasticConfig) -> Vec<EntryId> {
+    let mut done = vec![];
+    let mut rejected = vec![];
+    let mut pending: Vec<&StreamMsg> = msgs.iter().collect();
+    let mut backoff = Backoff::new(&config.retry.backoff);
+
+    loop {
+        let body = || make_json_body(&pending, config).unwrap_or(vec![]);
+        let outcomes = match bulk_send_retrying(
+            nodes,
+            &config.index,
+            body,
+            pending.len(),
+            &config.transport_retry,
+        )
+        .await
+        {
+            Ok(outcomes) => outcomes,
+            Err(error) => {
+                println!(
+                    "Failed to send logs to elastic, leaving {} entries pending: {error}",
+                    pending.len()
+                );
+                break;
+            }
+        };
+        let mut retry = vec![];
+        let mut last_reason = String::new();
+        for (msg, outcome) in pending.into_iter().zip(outcomes) {
+            match outcome {
+                ItemOutcome::Created => done.push(msg.entry.clone()),
+                ItemOutcome::Retryable(reason) => {
+                    retry.push(msg);
+                    last_reason = reason;
+                }
+                ItemOutcome::Permanent { status, reason } => rejected.push((msg, status, reason)),
+            }
+        }
+        if retry.is_empty() {
+            break;
+        }
+        if backoff.attempt() + 1 >= config.retry.max_attempts {
+            println!(
+                "Giving up on {} documents after {} attempts, leaving them pending ({last_reason})",
+                retry.len(),
+                config.retry.max_attempts
+            );
             break;
         }
-        let body = make_json_body(&buffer).unwrap_or(vec![]);
-        let response = elastic_client
-            .bulk(elasticsearch::BulkParts::Index(&config.index))
-            .body(body)
-            .send()
-            .await;
+        let delay = backoff.next_delay();
         println!(
-            "sent {} logs to elastic, response OK: {:?} \n {:?}",
-            open,
-            response.is_ok(),
-            response
+            "Retrying {} rejected documents in {delay:?} ({last_reason})",
+            retry.len()
         );
-        buffer = Vec::with_capacity(config.chunk_size.into());
+        tokio::time::sleep(delay).await;
+        pending = retry;
+    }
+
+    if !rejected.is_empty() {
+        for (msg, status, reason) in &rejected {
+            println!(
+                "Elastic rejected entry {} from {} with status {status}: {reason}",
+                msg.entry.id, msg.entry.stream
+            );
+        }
+        done.extend(dead_letter(nodes, &rejected, config).await);
+    }
+    done
+}
+
+/// Size of a message in a bulk request body, including its action line
+fn bulk_size(msg: &StreamMsg, config: &ElasticConfig) -> usize {
+    let doc = json_from_msg(msg, &config.mapping)
+        .map(|doc| doc.to_string().len())
+        .unwrap_or(0);
+    bulk_action(msg, config).to_string().len() + doc + 2
+}
+
+/// Collect the next batch to send: up to `chunk_size` messages or `max_batch_bytes` of request
+/// body, or whatever has arrived when the flush interval runs out after the first message.
+/// A message which would take the batch over the byte cap is kept in `carry` for the next one.
+/// Returns None once the producer is gone and everything has been handed out.
+async fn next_batch(
+    rx: &mut mpsc::Receiver<StreamMsg>,
+    carry: &mut Option<StreamMsg>,
+    config: &ElasticConfig,
+) -> Option<Vec<StreamMsg>> {
+    let first = match carry.take() {
+        Some(msg) => msg,
+        None => rx.recv().await?,
+    };
+    let mut bytes = bulk_size(&first, config);
+    if bytes > config.max_batch_bytes {
+        println!(
+            "Entry {} from {} is {bytes} bytes, over the batch limit, sending it alone",
+            first.entry.id, first.entry.stream
+        );
+    }
+    let mut batch = Vec::with_capacity(config.chunk_size.into());
+    batch.push(first);
+    let deadline =
+        tokio::time::Instant::now() + Duration::from_millis(config.flush_interval_millis);
+
+    while batch.len() < config.chunk_size.into() && bytes < config.max_batch_bytes {
+        let msg = tokio::select! {
+            msg = rx.recv() => msg,
+            _ = tokio::time::sleep_until(deadline) => break,
+        };
+        let Some(msg) = msg else {
+            break;
+        };
+        let size = bulk_size(&msg, config);
+        if bytes + size > config.max_batch_bytes {
+            *carry = Some(msg);
+            break;
+        }
+        bytes += size;
+        batch.push(msg);
+    }
+    Some(batch)
+}
+
+pub async fn consumer_loop(
+    rx: &mut mpsc::Receiver<StreamMsg>,
+    ack_tx: mpsc::UnboundedSender<Vec<EntryId>>,
+    in_flight: Arc<InFlight>,
+    config: ElasticConfig,
+) {
+    let nodes = Arc::new(
+        NodePool::new(&config)
+            .await
+            .expect("Failed to connect to Elastic!"),
+    );
+    bootstrap(&nodes, &config)
+        .await
+        .expect("Failed to bootstrap Elastic!");
+    let sniffer = config
+        .sniff_interval_millis
+        .map(|millis| tokio::spawn(sniff_loop(nodes.clone(), Duration::from_millis(millis))));
+
+    let mut carry = None;
+    while let Some(batch) = next_batch(rx, &mut carry, &config).await {
+        let done = send_batch(&nodes, &batch, &config).await;
+        // Whatever isn't done is left pending, for this or another consumer to claim again
+        let given_up: Vec<EntryId> = batch
+            .iter()
+            .map(|msg| msg.entry.clone())
+            .filter(|entry| !done.contains(entry))
+            .collect();
+        in_flight.release(&given_up);
+        println!(
+            "sent {} logs to elastic, {} done, {} of {} queued",
+            batch.len(),
+            done.len(),
+            rx.len(),
+            rx.max_capacity()
+        );
+        if ack_tx.send(done).is_err() {
+            println!("Acknowledger dropped, consumer exiting");
+            sniffer.inspect(|s| s.abort());
+            return;
+        }
     }
     println!("Producer dropped, consumer exiting");
+    sniffer.inspect(|s| s.abort());
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::config::UrlPort;
+    use crate::redis_logs::{LogMsg, LogRecord};
 
     use super::*;
     use serde::{Deserialize, Serialize};
@@ -94,6 +440,19 @@ mod tests {
         level: String,
     }
 
+    impl From<DummyLog> for StreamMsg {
+        fn from(d: DummyLog) -> Self {
+            StreamMsg {
+                entry: EntryId {
+                    stream: "info/log".into(),
+                    id: format!("0-{}", d.msg.len()),
+                },
+                index: None,
+                payload: Payload::Log(Box::new(d.into())),
+            }
+        }
+    }
+
     // Implement LogRecord for test if not already present
     impl From<DummyLog> for LogMsg {
         fn from(d: DummyLog) -> Self {
@@ -102,91 +461,291 @@ mod tests {
                 service_name: "test_service".into(),
                 text: "...".into(),
                 record: LogRecord {
-                    elapsed: crate::redis_logs::Elapsed {
-                        repr: "".into(),
-                        seconds: 0.0,
-                    },
-                    exception: None,
-                    extra: {}.into(),
-                    file: crate::redis_logs::File {
-                        name: "".into(),
-                        path: "".into(),
-                    },
-                    function: "".into(),
+                    extra: serde_json::json!({}),
                     level: crate::redis_logs::LogLevel {
-                        icon: "".into(),
                         name: d.level,
                         no: 100,
+                        ..Default::default()
                     },
-                    line: 0,
                     message: d.msg,
-                    module: "".into(),
-                    name: "".into(),
-                    process: crate::redis_logs::NameId {
-                        name: "".into(),
-                        id: 0,
-                    },
-                    thread: crate::redis_logs::NameId {
-                        name: "".into(),
-                        id: 0,
-                    },
                     time: crate::redis_logs::Timestamp {
-                        repr: "".into(),
-                        timestamp: 0.0,
+                        timestamp: Some(0.0),
+                        ..Default::default()
                     },
+                    ..Default::default()
                 },
+                schema_version: crate::redis_logs::RECORD_SCHEMA,
+                other: Default::default(),
             }
         }
     }
 
     #[test]
     fn test_make_docs_values_empty() {
-        let records: Vec<LogMsg> = vec![];
-        let docs = make_json_body(&records).unwrap();
+        let records: Vec<&StreamMsg> = vec![];
+        let docs = make_json_body(&records, &batch_config(100, 1_000_000)).unwrap();
         assert!(docs.is_empty());
     }
 
     #[test]
     fn test_make_docs_values_single() {
-        let record: LogMsg = DummyLog {
+        let record: StreamMsg = DummyLog {
             msg: "hello".to_string(),
             level: "info".to_string(),
         }
         .into();
-        let docs = make_json_body(&vec![record.clone()]).unwrap();
+        let docs = make_json_body(&[&record], &batch_config(100, 1_000_000)).unwrap();
         // Each record should produce two JSON bodies (action + doc)
         assert_eq!(docs.len(), 2);
     }
 
     #[test]
     fn test_make_docs_values_multiple() {
-        let record1: LogMsg = DummyLog {
+        let record1: StreamMsg = DummyLog {
             msg: "a".to_string(),
             level: "info".to_string(),
         }
         .into();
-        let record2: LogMsg = DummyLog {
+        let record2: StreamMsg = DummyLog {
             msg: "b".to_string(),
             level: "warn".to_string(),
         }
         .into();
-        let docs = make_json_body(&vec![record1, record2]).unwrap();
+        let config = batch_config(100, 1_000_000);
+        let docs = make_json_body(&[&record1, &record2], &config).unwrap();
         assert_eq!(docs.len(), 4);
     }
 
     #[test]
-    fn test_elastic_client_invalid_url() {
-        let result = elastic_client(&ElasticConfig {
-            url: UrlPort {
-                url: "not an url".into(),
-                port: 9876,
-            },
-            api_key: Some("key".into()),
-            username: None,
-            password: None,
-            chunk_size: 8,
-            index: "".into(),
+    fn test_item_outcomes() {
+        let response = serde_json::json!({
+            "errors": true,
+            "items": [
+                { "create": { "status": 201 } },
+                { "create": { "status": 429, "error": {
+                    "type": "es_rejected_execution_exception",
+                    "reason": "rejected execution"
+                } } },
+                { "create": { "status": 400, "error": {
+                    "type": "document_parsing_exception",
+                    "reason": "failed to parse field [line]"
+                } } },
+                { "create": { "status": 409 } },
+            ]
         });
-        assert!(result.is_err());
+        assert_eq!(
+            item_outcomes(&response, 5),
+            vec![
+                ItemOutcome::Created,
+                ItemOutcome::Retryable(
+                    "es_rejected_execution_exception: rejected execution".into()
+                ),
+                ItemOutcome::Permanent {
+                    status: 400,
+                    reason: "document_parsing_exception: failed to parse field [line]".into()
+                },
+                ItemOutcome::Created,
+                ItemOutcome::Retryable(format!("No result in bulk response: {response}")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_item_outcomes_no_items() {
+        let response = serde_json::json!({ "error": "something went wrong", "status": 500 });
+        let outcomes = item_outcomes(&response, 2);
+        assert_eq!(outcomes.len(), 2);
+        assert!(
+            outcomes
+                .iter()
+                .all(|o| matches!(o, ItemOutcome::Retryable(_)))
+        );
+    }
+
+    #[test]
+    fn test_transport_retry_exhausted() {
+        let config: TransportRetryConfig =
+            toml::from_str("max_attempts = 3\nmax_elapsed_millis = 1000").unwrap();
+        let ms = Duration::from_millis;
+        assert!(!transport_retry_exhausted(1, ms(0), &config));
+        assert!(!transport_retry_exhausted(2, ms(999), &config));
+        assert!(transport_retry_exhausted(3, ms(0), &config));
+        assert!(transport_retry_exhausted(1, ms(1000), &config));
+
+        let unlimited: TransportRetryConfig = toml::from_str("max_elapsed_millis = 1000").unwrap();
+        assert!(!transport_retry_exhausted(100, ms(999), &unlimited));
+    }
+
+    fn batch_config(chunk_size: u16, max_batch_bytes: usize) -> ElasticConfig {
+        toml::from_str(&format!(
+            "
+url = {{ url = \"http://localhost\", port = 9200 }}
+chunk_size = {chunk_size}
+flush_interval_millis = 50
+max_batch_bytes = {max_batch_bytes}
+"
+        ))
+        .unwrap()
+    }
+
+    fn dummy_msg(msg: &str) -> StreamMsg {
+        DummyLog {
+            msg: msg.into(),
+            level: "info".into(),
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_chunk_size_and_flush() {
+        let config = batch_config(2, 1_000_000);
+        let (tx, mut rx) = mpsc::channel(8);
+        for msg in ["a", "bb", "ccc"] {
+            tx.send(dummy_msg(msg)).await.unwrap();
+        }
+        let mut carry = None;
+        let batch = next_batch(&mut rx, &mut carry, &config).await.unwrap();
+        assert_eq!(batch.len(), 2);
+        // The third message is sent on its own once the flush interval runs out
+        let batch = next_batch(&mut rx, &mut carry, &config).await.unwrap();
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].entry.id, "0-3");
+        drop(tx);
+        assert!(next_batch(&mut rx, &mut carry, &config).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_byte_cap() {
+        let size = bulk_size(&dummy_msg("a"), &batch_config(100, 1_000_000));
+        let config = batch_config(100, size * 2);
+        let (tx, mut rx) = mpsc::channel(8);
+        for msg in ["a", "b", "c"] {
+            tx.send(dummy_msg(msg)).await.unwrap();
+        }
+        drop(tx);
+        let mut carry = None;
+        let batch = next_batch(&mut rx, &mut carry, &config).await.unwrap();
+        assert_eq!(batch.len(), 2);
+        assert!(carry.is_none());
+        let batch = next_batch(&mut rx, &mut carry, &config).await.unwrap();
+        assert_eq!(batch.len(), 1);
+        assert!(next_batch(&mut rx, &mut carry, &config).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_carries_over_cap() {
+        let size = bulk_size(&dummy_msg("a"), &batch_config(100, 1_000_000));
+        let config = batch_config(100, size + 1);
+        let (tx, mut rx) = mpsc::channel(8);
+        tx.send(dummy_msg("a")).await.unwrap();
+        tx.send(dummy_msg("a much longer message")).await.unwrap();
+        drop(tx);
+        let mut carry = None;
+        let batch = next_batch(&mut rx, &mut carry, &config).await.unwrap();
+        assert_eq!(batch.len(), 1);
+        assert!(carry.is_some());
+        let batch = next_batch(&mut rx, &mut carry, &config).await.unwrap();
+        assert_eq!(batch[0].entry.id, "0-21");
+        assert!(next_batch(&mut rx, &mut carry, &config).await.is_none());
+    }
+
+    #[test]
+    fn test_dead_letter_doc() {
+        let config: ElasticConfig = toml::from_str(
+            "
+url = { url = \"http://localhost\", port = 9200 }
+index = \"bec-logs\"
+",
+        )
+        .unwrap();
+        let msg: StreamMsg = DummyLog {
+            msg: "a".to_string(),
+            level: "info".to_string(),
+        }
+        .into();
+        let doc = dead_letter_doc(&msg, 400, "mapper_parsing_exception: nope", &config);
+        assert_eq!(doc["stream_id"], "0-1");
+        assert_eq!(doc["index"], "bec-logs");
+        assert_eq!(doc["status"], 400);
+        let original: serde_json::Value =
+            serde_json::from_str(doc["document"].as_str().unwrap()).unwrap();
+        assert_eq!(original["message"], "a");
+    }
+
+    #[test]
+    fn test_make_docs_stream_index_and_tag() {
+        let mut record: StreamMsg = DummyLog {
+            msg: "a".to_string(),
+            level: "info".to_string(),
+        }
+        .into();
+        let config = batch_config(100, 1_000_000);
+        assert_eq!(
+            bulk_action(&record, &config),
+            serde_json::json!({ "create": { "_id": "info/log-0-1" } })
+        );
+        record.index = Some("bec-other".into());
+        assert_eq!(
+            bulk_action(&record, &config),
+            serde_json::json!({ "create": { "_index": "bec-other", "_id": "info/log-0-1" } })
+        );
+        let doc = json_from_msg(&dummy_msg("a"), &config.mapping).unwrap();
+        assert_eq!(doc["redis_stream"], "info/log");
+        assert_eq!(doc["message"], "a");
+    }
+
+    #[test]
+    fn test_document_id() {
+        let config = batch_config(100, 1_000_000);
+        let action = |msg: &StreamMsg| bulk_action(msg, &config)["create"]["_id"].clone();
+        // A re-sent entry gets the same ID, so Elastic answers 409 instead of indexing it again
+        assert_eq!(action(&dummy_msg("a")), action(&dummy_msg("a")));
+        assert_eq!(action(&dummy_msg("a")), "info/log-0-1");
+        assert_ne!(action(&dummy_msg("a")), action(&dummy_msg("ab")));
+        let mut other_stream = dummy_msg("a");
+        other_stream.entry.stream = "scans".into();
+        assert_eq!(action(&other_stream), "scans-0-1");
+    }
+
+    #[test]
+    fn test_bulk_action_templated_index() {
+        let mut config = batch_config(100, 1_000_000);
+        config.index = "bec-{service_name}-{%Y.%m.%d}".into();
+        let mut record = dummy_msg("a");
+        assert_eq!(
+            bulk_action(&record, &config)["create"]["_index"],
+            "bec-test_service-1970.01.01"
+        );
+        record.index = Some("bec-{log_type}".into());
+        assert_eq!(
+            bulk_action(&record, &config)["create"]["_index"],
+            "bec-info"
+        );
+        let doc = dead_letter_doc(&record, 400, "nope", &config);
+        assert_eq!(doc["index"], "bec-info");
+    }
+
+    #[test]
+    fn test_json_from_msg_value() {
+        let msg = StreamMsg {
+            entry: EntryId {
+                stream: "scans".into(),
+                id: "1-0".into(),
+            },
+            index: None,
+            payload: Payload::Value(serde_json::json!({ "scan_number": 5 })),
+        };
+        let mapping = MappingConfig::default();
+        let doc = json_from_msg(&msg, &mapping).unwrap();
+        assert_eq!(doc["scan_number"], 5);
+        assert_eq!(doc["redis_stream"], "scans");
+        assert!(doc["@timestamp"].is_string());
+
+        let msg = StreamMsg {
+            payload: Payload::Value(serde_json::json!([1, 2, 3])),
+            ..msg
+        };
+        let doc = json_from_msg(&msg, &mapping).unwrap();
+        assert_eq!(doc["data"], serde_json::json!([1, 2, 3]));
     }
 }
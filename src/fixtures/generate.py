@@ -0,0 +1,127 @@
+#!/usr/bin/env python3
+"""Write the LogMessage fixtures in this directory.
+
+The fixtures are synthetic: they are not captured from a running BEC, but built here to follow
+the layouts the decoder has to handle. See README.md. Run from anywhere with
+`python3 src/fixtures/generate.py`; it needs no packages beyond the standard library.
+"""
+
+import pathlib
+import struct
+
+HERE = pathlib.Path(__file__).parent
+
+
+def packb(value) -> bytes:
+    """Msgpack a value the way msgpack-python's packb does for these types"""
+    if value is None:
+        return b"\xc0"
+    if isinstance(value, bool):
+        return b"\xc3" if value else b"\xc2"
+    if isinstance(value, int):
+        if 0 <= value < 0x80:
+            return bytes([value])
+        for tag, fmt, limit in ((0xCC, ">B", 1 << 8), (0xCD, ">H", 1 << 16), (0xCE, ">I", 1 << 32)):
+            if 0 <= value < limit:
+                return bytes([tag]) + struct.pack(fmt, value)
+        raise ValueError(f"unsupported int {value}")
+    if isinstance(value, float):
+        return b"\xcb" + struct.pack(">d", value)
+    if isinstance(value, str):
+        data = value.encode()
+        if len(data) < 32:
+            return bytes([0xA0 | len(data)]) + data
+        if len(data) < 1 << 8:
+            return b"\xd9" + bytes([len(data)]) + data
+        return b"\xda" + struct.pack(">H", len(data)) + data
+    if isinstance(value, dict):
+        if len(value) >= 16:
+            raise ValueError("map too large")
+        out = bytes([0x80 | len(value)])
+        for key, item in value.items():
+            out += packb(key) + packb(item)
+        return out
+    raise TypeError(type(value))
+
+
+def codec(encoder_name: str, type_name: str, data) -> dict:
+    """A value wrapped in BEC's codec envelope"""
+    return {
+        "__bec_codec__": {"encoder_name": encoder_name, "type_name": type_name, "data": data}
+    }
+
+
+def log_message(log_type: str, log_msg) -> dict:
+    return codec(
+        "BECMessageEncoder",
+        "LogMessage",
+        {"log_type": log_type, "log_msg": log_msg, "metadata": {}},
+    )
+
+
+def record(**extra_fields) -> dict:
+    """A loguru record as BEC's logger serializes it, for a scan server INFO line"""
+    return {
+        "elapsed": {"repr": "0:00:05.000100", "seconds": 5.0001},
+        "exception": None,
+        "extra": {},
+        "file": {"name": "scan_server.py", "path": "/bec/scan_server/scan_server.py"},
+        "function": "start_scan",
+        "level": {"icon": "ℹ️", "name": "INFO", "no": 20},
+        "line": 118,
+        "message": "Scan 12 started",
+        "module": "scan_server",
+        "name": "bec_server.scan_server",
+        "process": {"name": "MainProcess", "id": 4242},
+        "thread": {"name": "MainThread", "id": 140234},
+        "time": {"repr": "2024-03-05 13:00:00.123456+01:00", "timestamp": 1709640000.123456},
+        **extra_fields,
+    }
+
+
+def current() -> dict:
+    """The record, its service and the formatted line"""
+    return log_message(
+        "info",
+        {"record": record(), "service_name": "ScanServer", "text": "Scan 12 started\n"},
+    )
+
+
+def text() -> dict:
+    """Just the formatted line, as the log_msg"""
+    return log_message("error", "2024-03-05 13:00:00 | ScanServer | ERROR | Scan 12 failed\n")
+
+
+def partial() -> dict:
+    """A record missing the optional fields, with no text"""
+    rec = record()
+    for key in ("elapsed", "file", "thread"):
+        del rec[key]
+    rec["time"] = {"timestamp": 1709640000.123456}
+    return log_message("info", {"record": rec, "service_name": "ScanServer"})
+
+
+def extended() -> dict:
+    """Fields the decoder doesn't know at several levels, and a codec value in extra"""
+    rec = record(context={"scan_id": "d1f2"})
+    rec["extra"] = {"status": codec("EnumEncoder", "ScanStatus", "open")}
+    rec["level"]["color"] = "<bold>"
+    return log_message(
+        "info",
+        {
+            "record": rec,
+            "service_name": "ScanServer",
+            "text": "Scan 12 started\n",
+            "hostname": "beamline-x",
+        },
+    )
+
+
+if __name__ == "__main__":
+    for name, message in [
+        ("current", current()),
+        ("text", text()),
+        ("partial", partial()),
+        ("extended", extended()),
+    ]:
+        (HERE / f"log_message_{name}.msgpack").write_bytes(packb(message))
@@ -0,0 +1,266 @@
+use chrono::{DateTime, Utc};
+
+use crate::redis_logs::{Payload, StreamMsg};
+
+/// Characters Elastic doesn't allow in index names
+const INVALID_CHARS: &[char] = &['\\', '/', '*', '?', '"', '<', '>', '|', ' ', ',', '#', ':'];
+const MAX_INDEX_BYTES: usize = 255;
+/// Stands in for fields which a message doesn't have
+const MISSING_FIELD: &str = "unknown";
+
+#[derive(Debug, PartialEq)]
+enum Part<'a> {
+    Literal(&'a str),
+    /// A field of the message, e.g. `{service_name}`
+    Field(&'a str),
+    /// The message's timestamp in a chrono format, e.g. `{%Y.%m.%d}`
+    Date(&'a str),
+}
+
+/// Split an index name template such as `bec-{service_name}-{%Y.%m.%d}` into its parts
+fn parse(template: &str) -> Result<Vec<Part<'_>>, String> {
+    let mut parts = vec![];
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            parts.push(Part::Literal(&rest[..start]));
+        }
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unclosed {{ in index name {template}"))?;
+        let placeholder = &rest[start + 1..start + end];
+        parts.push(match placeholder.strip_prefix('%') {
+            Some(_) => Part::Date(placeholder),
+            None if placeholder.is_empty() => {
+                return Err(format!("Empty {{}} in index name {template}"));
+            }
+            None => Part::Field(placeholder),
+        });
+        rest = &rest[start + end + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Literal(rest));
+    }
+    Ok(parts)
+}
+
+pub fn is_template(index: &str) -> bool {
+    index.contains('{')
+}
+
+/// Check that an index name template can be used, including its date formats
+pub fn validate(template: &str) -> Result<(), String> {
+    for part in parse(template)? {
+        if let Part::Date(format) = part {
+            let items = chrono::format::StrftimeItems::new(format);
+            if items
+                .into_iter()
+                .any(|item| item == chrono::format::Item::Error)
+            {
+                return Err(format!(
+                    "Invalid date format {{{format}}} in index name {template}"
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A wildcard pattern matching every index the template can produce, e.g. for an index template
+pub fn pattern(template: &str) -> String {
+    match parse(template) {
+        Ok(parts) => parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(literal) => literal,
+                _ => "*",
+            })
+            .collect(),
+        Err(_) => template.to_owned(),
+    }
+}
+
+/// Make a name valid for Elastic: lowercase, without forbidden characters or leading `-`, `_`, `+`
+/// or `.`, and no longer than 255 bytes
+pub fn sanitize(name: &str) -> String {
+    let name: String = name
+        .to_lowercase()
+        .chars()
+        .map(|c| if INVALID_CHARS.contains(&c) { '_' } else { c })
+        .collect();
+    let mut name = name.trim_start_matches(['-', '_', '+', '.']).to_owned();
+    if name.len() > MAX_INDEX_BYTES {
+        let mut end = MAX_INDEX_BYTES;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+    }
+    name
+}
+
+fn field(msg: &StreamMsg, name: &str) -> Option<String> {
+    match &msg.payload {
+        Payload::Log(log) => match name {
+            "service_name" => Some(log.service_name.clone()),
+            "log_type" | "level" => Some(log.record.level.name.clone()),
+            "module" => Some(log.record.module.clone()),
+            "function" => Some(log.record.function.clone()),
+            "name" => Some(log.record.name.clone()),
+            "redis_stream" => Some(msg.entry.stream.clone()),
+            _ => None,
+        },
+        Payload::Value(value) => match name {
+            "redis_stream" => Some(msg.entry.stream.clone()),
+            _ => value[name].as_str().map(str::to_owned),
+        },
+    }
+}
+
+fn timestamp(msg: &StreamMsg) -> DateTime<Utc> {
+    match &msg.payload {
+        Payload::Log(log) => log.record.time.utc().unwrap_or_else(Utc::now),
+        Payload::Value(_) => Utc::now(),
+    }
+}
+
+/// Sanitize a name, or a part of one, falling back to `unknown` if nothing is left of it
+fn sanitize_or_missing(name: &str) -> String {
+    match sanitize(name) {
+        name if name.is_empty() => MISSING_FIELD.into(),
+        name => name,
+    }
+}
+
+/// The index for a message, from its stream's index or the default one, filling in any fields
+/// and dates in it
+pub fn render(template: &str, msg: &StreamMsg) -> String {
+    if !is_template(template) {
+        return template.to_owned();
+    }
+    let Ok(parts) = parse(template) else {
+        return sanitize_or_missing(template);
+    };
+    let name: String = parts
+        .iter()
+        .map(|part| match part {
+            Part::Literal(literal) => literal.to_string(),
+            Part::Field(name) => sanitize_or_missing(&field(msg, name).unwrap_or_default()),
+            Part::Date(format) => timestamp(msg).format(format).to_string(),
+        })
+        .collect();
+    sanitize_or_missing(&name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis_logs::{EntryId, LogMsg};
+
+    fn log_msg(service_name: &str, timestamp: f64) -> StreamMsg {
+        let name_id = serde_json::json!({ "name": "", "id": 0 });
+        let log: LogMsg = serde_json::from_value(serde_json::json!({
+            "record": {
+                "elapsed": { "repr": "", "seconds": 0.0 },
+                "exception": null,
+                "extra": {},
+                "file": { "name": "", "path": "" },
+                "function": "",
+                "level": { "icon": "", "name": "INFO", "no": 20 },
+                "line": 0,
+                "message": "",
+                "module": "",
+                "name": "",
+                "process": name_id,
+                "thread": name_id,
+                "time": { "repr": "", "timestamp": timestamp },
+            },
+            "service_name": service_name,
+            "text": "",
+        }))
+        .unwrap();
+        StreamMsg {
+            entry: EntryId {
+                stream: "info/log".into(),
+                id: "0-1".into(),
+            },
+            index: None,
+            payload: Payload::Log(Box::new(log)),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("bec-{service_name}-{%Y.%m}").unwrap(),
+            vec![
+                Part::Literal("bec-"),
+                Part::Field("service_name"),
+                Part::Literal("-"),
+                Part::Date("%Y.%m"),
+            ]
+        );
+        assert_eq!(parse("plain").unwrap(), vec![Part::Literal("plain")]);
+        assert!(parse("bec-{service_name").is_err());
+        assert!(parse("bec-{}").is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate("bec-{service_name}-{%Y.%m.%d}").is_ok());
+        assert!(validate("bec-{%Q}").is_err());
+        assert!(validate("bec-{oops").is_err());
+    }
+
+    #[test]
+    fn test_render() {
+        // 2024-03-05T12:00:00Z
+        let msg = log_msg("ScanServer", 1_709_640_000.5);
+        assert_eq!(
+            render("bec-{service_name}-{%Y.%m.%d}", &msg),
+            "bec-scanserver-2024.03.05"
+        );
+        assert_eq!(render("bec-logs", &msg), "bec-logs");
+        assert_eq!(render("bec-{nonexistent}", &msg), "bec-unknown");
+    }
+
+    #[test]
+    fn test_render_empty_fields() {
+        let msg = log_msg("", 0.0);
+        assert_eq!(render("bec-{service_name}", &msg), "bec-unknown");
+        assert_eq!(render("{service_name}", &msg), "unknown");
+        assert_eq!(render("{service_name}", &log_msg("-.", 0.0)), "unknown");
+        assert_eq!(
+            render("{service_name}-logs", &log_msg("..", 0.0)),
+            "unknown-logs"
+        );
+        assert_eq!(render(".{service_name}", &log_msg("Scan", 0.0)), "scan");
+    }
+
+    #[test]
+    fn test_render_value() {
+        let msg = StreamMsg {
+            entry: EntryId {
+                stream: "scans/status".into(),
+                id: "0-1".into(),
+            },
+            index: None,
+            payload: Payload::Value(serde_json::json!({ "kind": "Scan" })),
+        };
+        assert_eq!(render("bec-{kind}", &msg), "bec-scan");
+        assert_eq!(render("bec-{redis_stream}", &msg), "bec-scans_status");
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("BEC Logs/Device*Server"), "bec_logs_device_server");
+        assert_eq!(sanitize("_-+.bec"), "bec");
+        assert_eq!(sanitize(&"ü".repeat(200)).len(), 254);
+    }
+
+    #[test]
+    fn test_pattern() {
+        assert_eq!(pattern("bec-{service_name}-{%Y.%m.%d}"), "bec-*-*");
+        assert_eq!(pattern("bec-logs"), "bec-logs");
+    }
+}
@@ -1,14 +1,22 @@
-use std::process::exit;
+use std::{process::exit, sync::Arc};
 
 use tokio::sync::mpsc;
 
+mod backoff;
+mod bec_codec;
+mod bootstrap;
+
 mod redis_logs;
-use crate::redis_logs::{LogMsg, producer_loop};
+use crate::redis_logs::{EntryId, InFlight, StreamMsg, ack_loop, producer_loop};
 
 mod elastic_push;
+mod index_name;
+mod mapping;
 use crate::elastic_push::consumer_loop;
 
 mod config;
+mod nodes;
+mod tls;
 use crate::config::IngestorConfig;
 
 use clap::Parser;
@@ -40,11 +48,19 @@ fn entry() -> IngestorConfig {
 async fn main_loop(config: IngestorConfig) {
     println!("Starting log ingestor with config: \n {:?}", &config);
 
-    let (tx, mut rx) = mpsc::unbounded_channel::<LogMsg>();
-    let producer = tokio::spawn(producer_loop(tx, config.redis.clone()));
-    consumer_loop(&mut rx, config.elastic.clone()).await;
+    let (tx, mut rx) = mpsc::channel::<StreamMsg>(config.queue_capacity.max(1));
+    let (ack_tx, ack_rx) = mpsc::unbounded_channel::<Vec<EntryId>>();
+    let in_flight = Arc::new(InFlight::default());
+    let producer = tokio::spawn(producer_loop(tx, in_flight.clone(), config.redis.clone()));
+    let acknowledger = tokio::spawn(ack_loop(ack_rx, in_flight.clone(), config.redis.clone()));
+    consumer_loop(&mut rx, ack_tx, in_flight, config.elastic.clone()).await;
 
-    let _ = tokio::join!(producer);
+    // A task which panicked must not look like a clean shutdown to whatever restarts us
+    let (producer, acknowledger) = tokio::join!(producer, acknowledger);
+    if let Err(e) = producer.and(acknowledger) {
+        println!("Log ingestor task failed: {e}");
+        exit(1)
+    }
 }
 
 #[tokio::main]
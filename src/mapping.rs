@@ -0,0 +1,381 @@
+use serde_json::{Map, Value};
+
+use crate::{
+    config::MappingConfig,
+    redis_logs::{LogMsg, UnknownFields},
+};
+
+/// The value at a dotted path, if there is one
+fn get_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, |value, key| value.get(key))
+}
+
+/// Set the value at a dotted path, making objects along the way. Anything which isn't an object
+/// in the way is replaced.
+fn set_path(doc: &mut Map<String, Value>, path: &str, value: Value) {
+    let mut keys = path.split('.').peekable();
+    let mut current = doc;
+    while let Some(key) = keys.next() {
+        if keys.peek().is_none() {
+            current.insert(key.into(), value);
+            return;
+        }
+        let next = current
+            .entry(key)
+            .or_insert_with(|| Value::Object(Map::new()));
+        if !next.is_object() {
+            *next = Value::Object(Map::new());
+        }
+        current = next.as_object_mut().expect("Just made it an object");
+    }
+}
+
+/// Remove the value at a dotted path, if there is one
+fn remove_path(doc: &mut Map<String, Value>, path: &str) {
+    match path.split_once('.') {
+        None => {
+            doc.remove(path);
+        }
+        Some((key, rest)) => {
+            if let Some(Value::Object(inner)) = doc.get_mut(key) {
+                remove_path(inner, rest);
+            }
+        }
+    }
+}
+
+/// Version of the Elastic Common Schema the ECS profile follows
+const ECS_VERSION: &str = "8.11.0";
+
+/// The parts of an exception as `type`, `message` and `stack_trace`. Exceptions are either an
+/// object like loguru's `{"type": "ValueError", "value": "...", "traceback": true}` or the
+/// formatted traceback, whose last line names the type. loguru only says whether there was a
+/// traceback, which is then part of the record's formatted `text`.
+fn error_fields(exception: &Value, text: &str) -> Value {
+    let mut error = Map::new();
+    match exception {
+        Value::Object(exception) => {
+            for (from, to) in [
+                ("type", "type"),
+                ("value", "message"),
+                ("message", "message"),
+            ] {
+                if let Some(value) = exception.get(from).filter(|v| !v.is_null()) {
+                    let value = value
+                        .as_str()
+                        .map_or_else(|| value.to_string(), str::to_owned);
+                    error.entry(to).or_insert(value.into());
+                }
+            }
+            match exception.get("traceback") {
+                Some(Value::String(traceback)) => {
+                    error.insert("stack_trace".into(), traceback.clone().into());
+                }
+                _ if !text.trim().is_empty() => {
+                    error.insert("stack_trace".into(), text.into());
+                }
+                _ => {}
+            }
+        }
+        Value::String(traceback) => {
+            error.insert("stack_trace".into(), traceback.clone().into());
+            let last = traceback.lines().rev().find(|line| !line.trim().is_empty());
+            if let Some((error_type, message)) = last.and_then(|line| line.split_once(": ")) {
+                error.insert("type".into(), error_type.trim().into());
+                error.insert("message".into(), message.into());
+            }
+        }
+        _ => {}
+    }
+    error.into()
+}
+
+/// The exception as an object, which is what the index template maps it as. A formatted
+/// traceback becomes `{"traceback": ...}` and any other value `{"value": ...}`.
+fn exception_object(exception: &Value) -> Value {
+    match exception {
+        Value::Object(_) | Value::Null => exception.clone(),
+        Value::String(traceback) => serde_json::json!({ "traceback": traceback }),
+        other => serde_json::json!({ "value": other }),
+    }
+}
+
+fn object(fields: &UnknownFields) -> Map<String, Value> {
+    fields.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+}
+
+/// The fields of a message the ingestor doesn't know, by where they are in the record, e.g.
+/// `{"context": ..., "level": {"color": ...}}`. Unknown fields next to the record come first.
+fn unknown_fields(msg: &LogMsg) -> Map<String, Value> {
+    let record = &msg.record;
+    let mut fields = object(&msg.other);
+    fields.extend(object(&record.other));
+    for (name, other) in [
+        ("elapsed", &record.elapsed.other),
+        ("file", &record.file.other),
+        ("level", &record.level.other),
+        ("process", &record.process.other),
+        ("thread", &record.thread.other),
+        ("time", &record.time.other),
+    ] {
+        if !other.is_empty() {
+            fields.insert(name.into(), object(other).into());
+        }
+    }
+    fields
+}
+
+/// The log message as JSON, with the fields worked out from it which mappings can refer to
+fn source(msg: &LogMsg) -> Result<Value, serde_json::Error> {
+    let mut source = serde_json::to_value(msg)?;
+    let ingested = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true);
+    // A record whose time can't be made out is still indexed, as of when it arrived
+    source["@timestamp"] = msg
+        .record
+        .time
+        .as_rfc3339()
+        .unwrap_or(ingested.clone())
+        .into();
+    source["@ingested"] = ingested.into();
+    if let Some(offset) = msg.record.time.offset() {
+        source["@timezone_offset"] = offset.into();
+    }
+    source["@duration_nanos"] = ((msg.record.elapsed.seconds * 1e9).round() as i64).into();
+    source["@ecs_version"] = ECS_VERSION.into();
+    if let Some(exception) = &msg.record.exception {
+        source["@error"] = error_fields(exception, &msg.text);
+        source["record"]["exception"] = exception_object(exception);
+    }
+    let unknown = unknown_fields(msg);
+    if !unknown.is_empty() {
+        source["@unknown_fields"] = unknown.into();
+    }
+    Ok(source)
+}
+
+/// Make the document for a log message from the fields the mapping includes. Fields the message
+/// doesn't have are left out.
+pub fn log_document(msg: &LogMsg, mapping: &MappingConfig) -> Result<Value, serde_json::Error> {
+    let source = source(msg)?;
+    let mut doc = Map::new();
+    for (from, to) in mapping.fields() {
+        if let Some(value) = get_path(&source, &from) {
+            set_path(&mut doc, &to, value.clone());
+        }
+    }
+    Ok(doc.into())
+}
+
+/// Add the mapping's static fields to a document and remove the ones it drops
+pub fn finish_document(doc: &mut Value, mapping: &MappingConfig) {
+    let Value::Object(doc) = doc else {
+        return;
+    };
+    for (path, value) in &mapping.static_fields {
+        set_path(doc, path, value.clone());
+    }
+    for path in &mapping.drop {
+        remove_path(doc, path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_msg() -> LogMsg {
+        serde_json::from_value(serde_json::json!({
+            "record": {
+                "elapsed": { "repr": "0:00:01", "seconds": 1.0 },
+                "exception": null,
+                "extra": { "scan_id": "abc" },
+                "file": { "name": "scan.py", "path": "/bec/scan.py" },
+                "function": "run",
+                "level": { "icon": "", "name": "INFO", "no": 20 },
+                "line": 12,
+                "message": "Scan started",
+                "module": "scan",
+                "name": "bec",
+                "process": { "name": "MainProcess", "id": 42 },
+                "thread": { "name": "MainThread", "id": 1 },
+                "time": { "repr": "1970-01-01 01:00:00+01:00", "timestamp": 0.0 },
+            },
+            "service_name": "ScanServer",
+            "text": "Scan started\n",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_default_mapping() {
+        let doc = log_document(&log_msg(), &MappingConfig::default()).unwrap();
+        assert_eq!(
+            doc,
+            serde_json::json!({
+                "@timestamp": "1970-01-01T00:00:00.000000Z",
+                "event": { "ingested": doc["event"]["ingested"] },
+                "timezone": "+01:00",
+                "exception": null,
+                "file": { "name": "scan.py", "path": "/bec/scan.py" },
+                "function": "run",
+                "log_type": "INFO",
+                "line": 12,
+                "message": "Scan started",
+                "module": "scan",
+                "proc_id": 42,
+                "schema_version": 2,
+                "service_name": "ScanServer",
+            })
+        );
+    }
+
+    #[test]
+    fn test_custom_mapping() {
+        let mapping: MappingConfig = toml::from_str(
+            r#"
+drop = ["file.path", "exception"]
+[include]
+"record.extra" = "labels"
+"record.level.no" = "log.level_no"
+"record.thread.name" = "process.thread.name"
+"text" = "event.original"
+"record.nonexistent" = "missing"
+[static_fields]
+"host.name" = "beamline"
+"#,
+        )
+        .unwrap();
+        let mut doc = log_document(&log_msg(), &mapping).unwrap();
+        finish_document(&mut doc, &mapping);
+        assert_eq!(doc["labels"]["scan_id"], "abc");
+        assert_eq!(doc["log"]["level_no"], 20);
+        assert_eq!(doc["process"]["thread"]["name"], "MainThread");
+        assert_eq!(doc["event"]["original"], "Scan started\n");
+        assert_eq!(doc["host"]["name"], "beamline");
+        assert_eq!(doc["file"], serde_json::json!({ "name": "scan.py" }));
+        assert!(doc.get("exception").is_none());
+        assert!(doc.get("missing").is_none());
+        // The defaults are still there
+        assert_eq!(doc["message"], "Scan started");
+    }
+
+    #[test]
+    fn test_replace_fields() {
+        let mapping: MappingConfig =
+            toml::from_str("profile = \"ecs\"\nfields = { \"record.message\" = \"msg\" }").unwrap();
+        let doc = log_document(&log_msg(), &mapping).unwrap();
+        assert_eq!(doc, serde_json::json!({ "msg": "Scan started" }));
+    }
+
+    #[test]
+    fn test_ecs_profile() {
+        let mapping: MappingConfig = toml::from_str("profile = \"ecs\"").unwrap();
+        let mut msg = log_msg();
+        msg.record.exception = Some(
+            "Traceback (most recent call last):\n  File \"scan.py\", line 12\nValueError: bad scan\n"
+                .into(),
+        );
+        let doc = log_document(&msg, &mapping).unwrap();
+        assert_eq!(doc["log"]["level"], "INFO");
+        assert_eq!(doc["log"]["logger"], "bec");
+        assert_eq!(
+            doc["log"]["origin"],
+            serde_json::json!({
+                "file": { "name": "scan.py", "path": "/bec/scan.py", "line": 12 },
+                "function": "run",
+            })
+        );
+        assert_eq!(doc["process"]["pid"], 42);
+        assert_eq!(doc["process"]["thread"]["name"], "MainThread");
+        assert_eq!(doc["service"]["name"], "ScanServer");
+        assert_eq!(doc["event"]["duration"], 1_000_000_000);
+        assert_eq!(doc["event"]["original"], "Scan started\n");
+        assert_eq!(doc["error"]["type"], "ValueError");
+        assert_eq!(doc["error"]["message"], "bad scan");
+        assert!(
+            doc["error"]["stack_trace"]
+                .as_str()
+                .unwrap()
+                .starts_with("Traceback")
+        );
+        assert_eq!(doc["bec"]["extra"]["scan_id"], "abc");
+        assert_eq!(doc["ecs"]["version"], ECS_VERSION);
+        assert_eq!(doc["bec"]["schema_version"], 2);
+        assert_eq!(doc["event"]["timezone"], "+01:00");
+        assert!(doc["event"]["ingested"].is_string());
+        assert!(doc.get("log_type").is_none());
+
+        let doc = log_document(&log_msg(), &mapping).unwrap();
+        assert!(doc.get("error").is_none());
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        let mut msg = log_msg();
+        msg.other.insert("hostname".into(), "beamline-x".into());
+        msg.record
+            .other
+            .insert("context".into(), serde_json::json!({ "scan_id": "d1f2" }));
+        msg.record
+            .level
+            .other
+            .insert("color".into(), "<bold>".into());
+        let doc = log_document(&msg, &MappingConfig::default()).unwrap();
+        assert_eq!(
+            doc["unknown_fields"],
+            serde_json::json!({
+                "hostname": "beamline-x",
+                "context": { "scan_id": "d1f2" },
+                "level": { "color": "<bold>" },
+            })
+        );
+        let doc = log_document(&log_msg(), &MappingConfig::default()).unwrap();
+        assert!(doc.get("unknown_fields").is_none());
+    }
+
+    #[test]
+    fn test_error_fields() {
+        let exception = serde_json::json!({
+            "type": "KeyError",
+            "value": "'x'",
+            "traceback": true,
+        });
+        let text = "Traceback (most recent call last):\n  ...\nKeyError: 'x'\n";
+        assert_eq!(
+            error_fields(&exception, text),
+            serde_json::json!({ "type": "KeyError", "message": "'x'", "stack_trace": text })
+        );
+        let exception = serde_json::json!({ "type": "KeyError", "traceback": "Traceback ..." });
+        assert_eq!(
+            error_fields(&exception, text),
+            serde_json::json!({ "type": "KeyError", "stack_trace": "Traceback ..." })
+        );
+        assert_eq!(
+            error_fields(&"no type here".into(), text),
+            serde_json::json!({ "stack_trace": "no type here" })
+        );
+    }
+
+    #[test]
+    fn test_exception_is_always_an_object() {
+        let mut msg = log_msg();
+        msg.record.exception = Some("Traceback ...\nKeyError: 'x'\n".into());
+        let doc = log_document(&msg, &MappingConfig::default()).unwrap();
+        assert_eq!(
+            doc["exception"],
+            serde_json::json!({ "traceback": "Traceback ...\nKeyError: 'x'\n" })
+        );
+        let exception = serde_json::json!({ "type": "KeyError", "traceback": true });
+        msg.record.exception = Some(exception.clone());
+        let doc = log_document(&msg, &MappingConfig::default()).unwrap();
+        assert_eq!(doc["exception"], exception);
+    }
+
+    #[test]
+    fn test_set_path_replaces_values_in_the_way() {
+        let mut doc = Map::new();
+        set_path(&mut doc, "a", 1.into());
+        set_path(&mut doc, "a.b", 2.into());
+        assert_eq!(Value::from(doc), serde_json::json!({ "a": { "b": 2 } }));
+    }
+}
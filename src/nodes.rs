@@ -0,0 +1,468 @@
+use std::{
+    error::Error,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use elasticsearch::{
+    Elasticsearch,
+    http::{Method, Url, headers::HeaderMap, request::JsonBody},
+};
+use tokio::time::Instant;
+
+use crate::{
+    backoff::Backoff,
+    config::ElasticConfig,
+    tls::{FingerprintCerts, cert_validation, client_certificate},
+};
+
+/// Build a client which talks to a single Elastic node
+async fn node_client(
+    config: &ElasticConfig,
+    url: &Url,
+    fingerprint_certs: &FingerprintCerts,
+) -> Result<Elasticsearch, Box<dyn Error>> {
+    let cert_validation = cert_validation(&config.tls, url, fingerprint_certs).await?;
+    let conn_pool = elasticsearch::http::transport::SingleNodeConnectionPool::new(url.clone());
+    let credentials = match (client_certificate(&config.tls)?, config.credentials()?) {
+        (Some(_), Some(_)) => {
+            return Err("A client certificate can't be combined with other credentials".into());
+        }
+        (Some(cert), None) => Some(elasticsearch::auth::Credentials::Certificate(cert)),
+        (None, credentials) => credentials,
+    };
+    let mut transport = elasticsearch::http::transport::TransportBuilder::new(conn_pool)
+        .cert_validation(cert_validation);
+    if let Some(credentials) = credentials {
+        transport = transport.auth(credentials);
+    }
+    Ok(Elasticsearch::new(transport.build()?))
+}
+
+struct Node {
+    url: Url,
+    /// None until a client could be set up, e.g. while the node can't be reached to fetch the
+    /// certificate for extra_ca_fingerprint
+    client: Option<Elasticsearch>,
+    /// Grows the time a node is left out for each time it fails in a row
+    backoff: Backoff,
+    dead_until: Option<Instant>,
+}
+
+impl Node {
+    fn is_alive(&self, now: Instant) -> bool {
+        self.dead_until.is_none_or(|until| until <= now)
+    }
+
+    /// Leave the node out for the next backoff delay, returning the delay
+    fn kill(&mut self) -> Duration {
+        let delay = self.backoff.next_delay();
+        self.dead_until = Some(Instant::now() + delay);
+        delay
+    }
+}
+
+/// The Elastic nodes to send requests to, in turn. Nodes which fail are left out for a while, and
+/// tried again once that has passed. If every node is out, the one due back first is used anyway.
+pub struct NodePool {
+    nodes: Mutex<Vec<Node>>,
+    seeds: Vec<Url>,
+    next: AtomicUsize,
+    config: ElasticConfig,
+    fingerprint_certs: FingerprintCerts,
+}
+
+impl NodePool {
+    /// Set up a client for every configured node. Nodes which can't be set up yet are left out
+    /// and tried again later, so that one node being down doesn't stop ingestion; it is only an
+    /// error if none of them can be.
+    pub async fn new(config: &ElasticConfig) -> Result<Self, Box<dyn Error>> {
+        let seeds = config.node_urls()?;
+        let fingerprint_certs = FingerprintCerts::default();
+        let mut nodes = vec![];
+        let mut last_error = None;
+        for url in &seeds {
+            let mut node = Self::node(config, url);
+            match node_client(config, url, &fingerprint_certs).await {
+                Ok(client) => node.client = Some(client),
+                Err(error) => {
+                    let delay = node.kill();
+                    println!("Can't set up elastic node {url}: {error}, retrying in {delay:?}");
+                    last_error = Some(error);
+                }
+            }
+            nodes.push(node);
+        }
+        if let Some(error) = last_error
+            && nodes.iter().all(|node| node.client.is_none())
+        {
+            return Err(error);
+        }
+        Ok(Self {
+            nodes: Mutex::new(nodes),
+            seeds,
+            next: AtomicUsize::new(0),
+            config: config.clone(),
+            fingerprint_certs,
+        })
+    }
+
+    fn node(config: &ElasticConfig, url: &Url) -> Node {
+        Node {
+            url: url.clone(),
+            client: None,
+            backoff: Backoff::new(&config.dead_node),
+            dead_until: None,
+        }
+    }
+
+    /// Try again to set up nodes which couldn't be, once they are due back
+    async fn connect_due(&self) {
+        let now = Instant::now();
+        let due: Vec<Url> = {
+            let mut nodes = self.nodes.lock().unwrap();
+            nodes
+                .iter_mut()
+                .filter(|node| node.client.is_none() && node.is_alive(now))
+                .map(|node| {
+                    // Keep other requests from trying it at the same time
+                    node.kill();
+                    node.url.clone()
+                })
+                .collect()
+        };
+        for url in due {
+            let result = node_client(&self.config, &url, &self.fingerprint_certs)
+                .await
+                .map_err(|e| e.to_string());
+            let mut nodes = self.nodes.lock().unwrap();
+            let Some(node) = nodes.iter_mut().find(|node| node.url == url) else {
+                continue;
+            };
+            match result {
+                Ok(client) => {
+                    node.client = Some(client);
+                    node.dead_until = None;
+                    node.backoff.reset();
+                    println!("Elastic node {url} is set up");
+                }
+                Err(error) => println!("Still can't set up elastic node {url}: {error}"),
+            }
+        }
+    }
+
+    /// The node to send the next request to, after setting up any nodes which are due to be
+    /// tried again
+    pub async fn next(&self) -> (Url, Elasticsearch) {
+        self.connect_due().await;
+        self.pick()
+    }
+
+    fn pick(&self) -> (Url, Elasticsearch) {
+        let nodes = self.nodes.lock().unwrap();
+        let now = Instant::now();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let (node, client) = (0..nodes.len())
+            .map(|i| &nodes[(start + i) % nodes.len()])
+            .filter_map(|node| Some((node, node.client.as_ref()?)))
+            .find(|(node, _)| node.is_alive(now))
+            .or_else(|| {
+                nodes
+                    .iter()
+                    .filter_map(|node| Some((node, node.client.as_ref()?)))
+                    .min_by_key(|(node, _)| node.dead_until)
+            })
+            .expect("Node pool always has a node which is set up");
+        (node.url.clone(), client.clone())
+    }
+
+    /// Leave a node out after a request to it failed
+    pub fn mark_dead(&self, url: &Url) {
+        let mut nodes = self.nodes.lock().unwrap();
+        if let Some(node) = nodes.iter_mut().find(|node| &node.url == url) {
+            let delay = node.kill();
+            println!("Elastic node {url} failed, leaving it out for {delay:?}");
+        }
+    }
+
+    /// Record that a request to a node succeeded
+    pub fn mark_alive(&self, url: &Url) {
+        let mut nodes = self.nodes.lock().unwrap();
+        if let Some(node) = nodes.iter_mut().find(|node| &node.url == url)
+            && node.dead_until.take().is_some()
+        {
+            node.backoff.reset();
+            println!("Elastic node {url} is back");
+        }
+    }
+
+    pub fn urls(&self) -> Vec<Url> {
+        let nodes = self.nodes.lock().unwrap();
+        nodes.iter().map(|node| node.url.clone()).collect()
+    }
+
+    /// Replace the pool with the configured nodes plus the given ones, keeping the state of nodes
+    /// which were already in it
+    async fn reseed(&self, discovered: Vec<Url>) {
+        let mut urls = self.seeds.clone();
+        for url in discovered {
+            if !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+        // Connecting to new nodes can take a while, so don't hold up requests meanwhile
+        let known = self.urls();
+        let mut added: Vec<Node> = vec![];
+        for url in urls.iter().filter(|url| !known.contains(url)) {
+            match node_client(&self.config, url, &self.fingerprint_certs)
+                .await
+                .map_err(|e| e.to_string())
+            {
+                Ok(client) => {
+                    println!("Discovered elastic node {url}");
+                    added.push(Node {
+                        client: Some(client),
+                        ..Self::node(&self.config, url)
+                    });
+                }
+                Err(error) => {
+                    println!("Can't connect to discovered elastic node {url}: {error}");
+                }
+            }
+        }
+
+        let mut nodes = self.nodes.lock().unwrap();
+        let mut old = std::mem::take(&mut *nodes);
+        for url in urls {
+            if let Some(i) = old.iter().position(|node| node.url == url) {
+                nodes.push(old.swap_remove(i));
+            } else if let Some(i) = added.iter().position(|node| node.url == url) {
+                nodes.push(added.swap_remove(i));
+            }
+        }
+    }
+
+    /// Send a request to the next node, returning the status and JSON body of the response
+    pub async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<(u16, serde_json::Value), elasticsearch::Error> {
+        let (url, client) = self.next().await;
+        let response = client
+            .transport()
+            .send(
+                method,
+                path,
+                HeaderMap::new(),
+                None::<&()>,
+                body.map(JsonBody::new),
+                None,
+            )
+            .await
+            .inspect_err(|_| self.mark_dead(&url))?;
+        self.mark_alive(&url);
+        let status = response.status_code().as_u16();
+        let text = response.text().await?;
+        Ok((status, serde_json::from_str(&text).unwrap_or_default()))
+    }
+
+    /// Ask the cluster which nodes it has, and add them to the pool
+    async fn sniff(&self) -> Result<(), Box<dyn Error>> {
+        let path = "/_nodes/http?filter_path=nodes.*.http.publish_address";
+        let (_, body) = self.send(Method::Get, path, None).await?;
+        let scheme = self.seeds[0].scheme();
+        let discovered = sniffed_urls(&body, scheme);
+        if discovered.is_empty() {
+            return Err(format!("No nodes found in sniff response: {body}").into());
+        }
+        self.reseed(discovered).await;
+        Ok(())
+    }
+}
+
+/// Turn a node's `publish_address` into a URL. Addresses are either `ip:port` or, when the node
+/// has a hostname, `hostname/ip:port`; the hostname is preferred so that TLS hostname
+/// verification works.
+fn publish_url(address: &str, scheme: &str) -> Option<Url> {
+    let address = match address.split_once('/') {
+        Some((host, ip_port)) => {
+            let (_, port) = ip_port.rsplit_once(':')?;
+            format!("{host}:{port}")
+        }
+        None => address.to_owned(),
+    };
+    Url::parse(&format!("{scheme}://{address}")).ok()
+}
+
+fn sniffed_urls(response: &serde_json::Value, scheme: &str) -> Vec<Url> {
+    let Some(nodes) = response["nodes"].as_object() else {
+        return vec![];
+    };
+    nodes
+        .values()
+        .filter_map(|node| node["http"]["publish_address"].as_str())
+        .filter_map(|address| publish_url(address, scheme))
+        .collect()
+}
+
+/// Periodically refresh the pool with the nodes the cluster reports
+pub async fn sniff_loop(pool: Arc<NodePool>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        match pool.sniff().await {
+            Ok(()) => println!(
+                "Elastic nodes: {}",
+                pool.urls()
+                    .iter()
+                    .map(Url::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Err(error) => println!("Failed to sniff elastic nodes: {error}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn pool(nodes: &[&str]) -> NodePool {
+        let nodes = nodes
+            .iter()
+            .map(|n| format!("\"{n}\""))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let config: ElasticConfig = toml::from_str(&format!("nodes = [{nodes}]")).unwrap();
+        NodePool::new(&config).await.unwrap()
+    }
+
+    fn url(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_invalid_url() {
+        let config: ElasticConfig =
+            toml::from_str("url = { url = \"not an url\", port = 9876 }").unwrap();
+        assert!(NodePool::new(&config).await.is_err());
+    }
+
+    /// Nodes trusting the certificate of a local test server, whose URL is returned
+    fn trusting_config(nodes: &[&str]) -> (ElasticConfig, Url) {
+        let (server, cert) = crate::tls::tests::test_server();
+        let fingerprint: String = cert
+            .digest(openssl::hash::MessageDigest::sha256())
+            .unwrap()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        let nodes = nodes
+            .iter()
+            .map(|n| format!("\"{}\"", n.replace("SERVER", server.as_str())))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let config = toml::from_str(&format!(
+            "nodes = [{nodes}]\ntls = {{ extra_ca_fingerprint = \"{fingerprint}\" }}"
+        ))
+        .unwrap();
+        (config, server)
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_node_left_out() {
+        let (config, server) = trusting_config(&["https://127.0.0.1:1", "SERVER"]);
+        let pool = NodePool::new(&config).await.unwrap();
+        assert_eq!(pool.urls().len(), 2);
+        for _ in 0..3 {
+            assert_eq!(pool.pick().0, server);
+        }
+
+        let (config, _) = trusting_config(&["https://127.0.0.1:1"]);
+        assert!(NodePool::new(&config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_round_robin() {
+        let pool = pool(&["http://es1:9200", "http://es2:9200"]).await;
+        let picked: Vec<Url> = (0..4).map(|_| pool.pick().0).collect();
+        assert_eq!(
+            picked,
+            [
+                url("http://es1:9200"),
+                url("http://es2:9200"),
+                url("http://es1:9200"),
+                url("http://es2:9200")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dead_nodes_skipped_and_resurrected() {
+        let pool = pool(&["http://es1:9200", "http://es2:9200"]).await;
+        pool.mark_dead(&url("http://es1:9200"));
+        for _ in 0..3 {
+            assert_eq!(pool.pick().0, url("http://es2:9200"));
+        }
+        // With every node out, the one due back first is tried
+        pool.mark_dead(&url("http://es2:9200"));
+        pool.mark_dead(&url("http://es2:9200"));
+        assert_eq!(pool.pick().0, url("http://es1:9200"));
+
+        pool.mark_alive(&url("http://es2:9200"));
+        assert_eq!(pool.pick().0, url("http://es2:9200"));
+        assert_eq!(pool.pick().0, url("http://es2:9200"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dead_node_comes_back_after_timeout() {
+        let pool = pool(&["http://es1:9200", "http://es2:9200"]).await;
+        pool.mark_dead(&url("http://es1:9200"));
+        assert_eq!(pool.pick().0, url("http://es2:9200"));
+        assert_eq!(pool.pick().0, url("http://es2:9200"));
+        tokio::time::advance(Duration::from_millis(1000)).await;
+        let picked: Vec<Url> = (0..2).map(|_| pool.pick().0).collect();
+        assert!(picked.contains(&url("http://es1:9200")));
+    }
+
+    #[test]
+    fn test_sniffed_urls() {
+        let response = serde_json::json!({
+            "nodes": {
+                "a": { "http": { "publish_address": "es1.example.com/10.0.0.1:9200" } },
+                "b": { "http": { "publish_address": "10.0.0.2:9201" } },
+                "c": { "http": {} },
+            }
+        });
+        let mut urls = sniffed_urls(&response, "https");
+        urls.sort();
+        assert_eq!(
+            urls,
+            [
+                url("https://10.0.0.2:9201"),
+                url("https://es1.example.com:9200")
+            ]
+        );
+        assert!(sniffed_urls(&serde_json::json!({}), "http").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reseed_keeps_seeds_and_state() {
+        let pool = pool(&["http://es1:9200"]).await;
+        pool.mark_dead(&url("http://es1:9200"));
+        pool.reseed(vec![url("http://es2:9200"), url("http://es1:9200")])
+            .await;
+        assert_eq!(
+            pool.urls(),
+            [url("http://es1:9200"), url("http://es2:9200")]
+        );
+        // es1 is still out
+        assert_eq!(pool.pick().0, url("http://es2:9200"));
+        assert_eq!(pool.pick().0, url("http://es2:9200"));
+    }
+}
@@ -1,47 +1,111 @@
-use chrono::TimeZone;
-use redis::Commands;
-use rmp_serde;
+use chrono::{DateTime, FixedOffset, SecondsFormat, Utc};
+use redis::{
+    AsyncCommands, IntoConnectionInfo, RedisFuture,
+    aio::{ConnectionLike, MultiplexedConnection},
+    cluster::ClusterClient,
+    cluster_async::ClusterConnection,
+    sentinel::{SentinelClient, SentinelClientBuilder, SentinelServerType},
+};
 use serde::{Deserialize, Serialize};
-use std::error::Error;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    error::Error,
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
 use tokio::sync::mpsc;
 
-use crate::config::RedisConfig;
+use crate::{
+    backoff::Backoff,
+    bec_codec,
+    config::{ClusterConfig, Decoder, RedisConfig, SentinelConfig, StreamConfig},
+};
 
-#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+/// Fields of a record which the ingestor doesn't know, e.g. ones added by a newer BEC release.
+/// They are kept where they were and also collected into `@unknown_fields` for indexing.
+pub type UnknownFields = BTreeMap<String, serde_json::Value>;
+
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize, Clone)]
+#[serde(default)]
 pub struct Elapsed {
     pub repr: String,
     pub seconds: f64,
+    #[serde(flatten)]
+    pub other: UnknownFields,
 }
 
-#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize, Clone)]
+#[serde(default)]
 pub struct File {
     pub name: String,
     pub path: String,
+    #[serde(flatten)]
+    pub other: UnknownFields,
 }
-#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize, Clone)]
+#[serde(default)]
 pub struct LogLevel {
     pub icon: String,
     pub name: String,
     pub no: usize,
+    #[serde(flatten)]
+    pub other: UnknownFields,
 }
-#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize, Clone)]
+#[serde(default)]
 pub struct NameId {
     pub name: String,
     pub id: usize,
+    #[serde(flatten)]
+    pub other: UnknownFields,
 }
-#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+/// When a record was logged, as Python's `str()` of the datetime, e.g.
+/// `2024-03-05 13:00:00.123456+01:00`, and as seconds since the epoch
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize, Clone)]
+#[serde(default)]
 pub struct Timestamp {
     pub repr: String,
-    pub timestamp: f64,
+    pub timestamp: Option<f64>,
+    #[serde(flatten)]
+    pub other: UnknownFields,
 }
 
 impl Timestamp {
-    pub fn as_rfc3339(&self) -> String {
-        let ts = chrono::Utc.timestamp_opt(self.timestamp as i64, 0).unwrap();
-        ts.to_rfc3339()
+    /// The time as logged, with its original offset, parsed from `repr`
+    fn parse_repr(&self) -> Option<DateTime<FixedOffset>> {
+        let repr = self.repr.trim();
+        DateTime::parse_from_rfc3339(repr)
+            .or_else(|_| DateTime::parse_from_str(repr, "%Y-%m-%d %H:%M:%S%.f%:z"))
+            .or_else(|_| DateTime::parse_from_str(repr, "%Y-%m-%dT%H:%M:%S%.f%:z"))
+            .ok()
+    }
+
+    /// The time in UTC to the microsecond, from `timestamp` or else from `repr` if it is missing
+    /// or out of range
+    pub fn utc(&self) -> Option<DateTime<Utc>> {
+        self.timestamp
+            .filter(|ts| ts.is_finite())
+            .and_then(|ts| DateTime::from_timestamp_micros((ts * 1e6).round() as i64))
+            .or_else(|| self.parse_repr().map(|time| time.to_utc()))
+    }
+
+    /// The UTC offset the record was logged with, e.g. `+01:00`
+    pub fn offset(&self) -> Option<String> {
+        self.parse_repr().map(|time| time.offset().to_string())
+    }
+
+    /// The time in RFC 3339 with microseconds, if it can be made out at all
+    pub fn as_rfc3339(&self) -> Option<String> {
+        self.utc()
+            .map(|time| time.to_rfc3339_opts(SecondsFormat::Micros, true))
     }
 }
-#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+/// A loguru record. Every field may be missing, as not every BEC release sends all of them.
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize, Clone)]
+#[serde(default)]
 pub struct LogRecord {
     pub elapsed: Elapsed,
     pub exception: Option<serde_json::Value>,
@@ -56,156 +120,599 @@ pub struct LogRecord {
     pub process: NameId,
     pub thread: NameId,
     pub time: Timestamp,
+    #[serde(flatten)]
+    pub other: UnknownFields,
+}
+
+/// Layout of a LogMessage's log_msg in early BEC releases: just the formatted line
+pub const TEXT_SCHEMA: u32 = 1;
+/// Layout of a LogMessage's log_msg in later BEC releases, which send a loguru record along with
+/// its service and the formatted line
+pub const RECORD_SCHEMA: u32 = 2;
+
+/// Default schema of a log message, a record
+fn default_schema_version() -> u32 {
+    RECORD_SCHEMA
 }
 
 #[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
 pub struct LogMsg {
+    #[serde(default)]
     pub record: LogRecord,
+    #[serde(default)]
     pub service_name: String,
+    #[serde(default)]
     pub text: String,
+    /// Which layout the message was sent in, worked out while decoding it
+    #[serde(default = "default_schema_version")]
+    pub schema_version: u32,
+    #[serde(flatten)]
+    pub other: UnknownFields,
 }
 
-#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
-struct LogMessage {
-    log_type: String,
-    log_msg: LogMsg,
-    metadata: serde_json::Value,
+impl LogMsg {
+    /// A message from a BEC release which only sent the formatted line
+    fn from_text(text: String) -> Self {
+        LogMsg {
+            record: LogRecord {
+                message: text.trim_end().to_owned(),
+                ..Default::default()
+            },
+            service_name: String::new(),
+            text,
+            schema_version: TEXT_SCHEMA,
+            other: UnknownFields::new(),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
-struct LogMessagePackInternal {
-    encoder_name: String,
-    type_name: String,
-    data: LogMessage,
+/// Identifies an entry in one of the configured Redis streams, so that it can be acknowledged once
+/// the document made from it has been persisted
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct EntryId {
+    pub stream: String,
+    pub id: String,
 }
 
-#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
-struct LogMessagePack {
-    #[serde(rename = "__bec_codec__")]
-    bec_codec: LogMessagePackInternal,
-}
-
-const LOGGING_ENDPOINT: [&str; 1] = ["info/log"];
-const KEY_MISMATCH: &str = "We got a response for request with one key, there must be one key!";
-const NO_DATA: &str = "Uh oh, log message contained no data";
-
-fn error_log_item() -> LogMessagePack {
-    LogMessagePack {
-        bec_codec: LogMessagePackInternal {
-            encoder_name: "".into(),
-            type_name: "".into(),
-            data: LogMessage {
-                log_type: "".into(),
-                log_msg: LogMsg {
-                    record: LogRecord {
-                        elapsed: Elapsed {
-                            repr: "".into(),
-                            seconds: 0.0,
-                        },
-                        exception: None,
-                        extra: {}.into(),
-                        file: File {
-                            name: "".into(),
-                            path: "".into(),
-                        },
-                        function: "".into(),
-                        level: LogLevel {
-                            icon: "".into(),
-                            name: "ERROR".into(),
-                            no: 100,
-                        },
-                        line: 0,
-                        message: "Error processing log messages from Redis!".into(),
-                        module: "".into(),
-                        name: "".into(),
-                        process: NameId {
-                            name: "".into(),
-                            id: 0,
-                        },
-                        thread: NameId {
-                            name: "".into(),
-                            id: 0,
-                        },
-                        time: Timestamp {
-                            repr: "".into(),
-                            timestamp: 0.0,
-                        },
-                    },
-                    service_name: "".into(),
-                    text: "".into(),
-                },
-                metadata: {}.into(),
+/// Entries this consumer has passed on and not yet acknowledged or given up on. They are still
+/// pending in Redis, so they mustn't be claimed again, by this consumer or another one, while
+/// Elastic is slow or down.
+#[derive(Debug, Default)]
+pub struct InFlight(Mutex<HashSet<EntryId>>);
+
+impl InFlight {
+    fn entries(&self) -> MutexGuard<'_, HashSet<EntryId>> {
+        // Nothing can panic while holding the lock, but carry on with the set if it did
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn insert(&self, entry: EntryId) {
+        self.entries().insert(entry);
+    }
+
+    fn contains(&self, entry: &EntryId) -> bool {
+        self.entries().contains(entry)
+    }
+
+    /// Forget entries which are done with, or left pending to be claimed again later
+    pub fn release(&self, entries: &[EntryId]) {
+        let mut in_flight = self.entries();
+        for entry in entries {
+            in_flight.remove(entry);
+        }
+    }
+
+    /// IDs of the entries in flight from a stream
+    fn ids(&self, stream: &str) -> Vec<String> {
+        self.entries()
+            .iter()
+            .filter(|e| e.stream == stream)
+            .map(|e| e.id.clone())
+            .collect()
+    }
+}
+
+/// The decoded contents of a stream entry
+#[derive(Debug, PartialEq, Clone)]
+pub enum Payload {
+    Log(Box<LogMsg>),
+    Value(serde_json::Value),
+}
+
+/// A decoded stream entry, along with where it came from and where it should go
+#[derive(Debug, PartialEq, Clone)]
+pub struct StreamMsg {
+    pub entry: EntryId,
+    /// Index configured for the stream, if it overrides the default
+    pub index: Option<String>,
+    pub payload: Payload,
+}
+
+const RECEIVER_DROPPED: &str = "Receiver dropped, stopping...";
+const NOT_BINARY: &str = "Log message data not binary-data!";
+/// Type name of the BEC messages published on info/log
+const LOG_MESSAGE: &str = "LogMessage";
+
+/// Number of entries which could not be decoded since startup
+static DECODE_FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+/// An ERROR level log message, as the base for the ones the ingestor writes itself
+fn error_log_item() -> LogMsg {
+    LogMsg {
+        record: LogRecord {
+            extra: serde_json::json!({}),
+            level: LogLevel {
+                name: "ERROR".into(),
+                no: 100,
+                ..Default::default()
             },
+            message: "Error processing log messages from Redis!".into(),
+            time: Timestamp {
+                timestamp: Some(0.0),
+                ..Default::default()
+            },
+            ..Default::default()
         },
+        service_name: "".into(),
+        text: "".into(),
+        schema_version: RECORD_SCHEMA,
+        other: UnknownFields::new(),
     }
 }
 
+/// A log message describing an entry which could not be decoded, so that the failure shows up
+/// in Elastic alongside the logs
+fn decode_error_msg(entry: &EntryId, error: &str) -> LogMsg {
+    let mut msg = error_log_item();
+    let now = chrono::Utc::now();
+    msg.service_name = "bec_log_ingestor".into();
+    msg.record.message = format!(
+        "Failed to decode entry {} from {}: {error}",
+        entry.id, entry.stream
+    );
+    msg.record.extra = serde_json::json!({
+        "stream": entry.stream,
+        "stream_id": entry.id,
+        "error": error,
+    });
+    msg.record.time = Timestamp {
+        repr: now.to_rfc3339(),
+        timestamp: Some(now.timestamp_micros() as f64 / 1e6),
+        other: UnknownFields::new(),
+    };
+    msg
+}
+
 fn str_error(err: &str) -> Box<dyn Error> {
     Box::<dyn Error>::from(err)
 }
 
-fn redis_conn(url: &str) -> Result<redis::Connection, redis::RedisError> {
-    let client = redis::Client::open(url)?;
-    client.get_connection()
+fn read_file(path: &std::path::Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    std::fs::read(path).map_err(|e| format!("Can't read {}: {e}", path.display()).into())
+}
+
+/// The CA and client certificates to use for `rediss://` connections
+fn tls_certificates(config: &RedisConfig) -> Result<redis::TlsCertificates, Box<dyn Error>> {
+    let root_cert = config.tls.ca_file.as_deref().map(read_file).transpose()?;
+    let client_tls = match &config.tls.client_cert {
+        Some(cert) => Some(redis::ClientTlsConfig {
+            client_cert: read_file(cert)?,
+            client_key: read_file(config.tls.client_key.as_ref().unwrap_or(cert))?,
+        }),
+        None => None,
+    };
+    Ok(redis::TlsCertificates {
+        client_tls,
+        root_cert,
+    })
+}
+
+fn is_tls(info: &redis::ConnectionInfo) -> bool {
+    matches!(info.addr, redis::ConnectionAddr::TcpTls { .. })
+}
+
+/// Make a client for the configured Redis, with the CA and client certificates if it uses TLS
+fn redis_client(config: &RedisConfig) -> Result<redis::Client, Box<dyn Error>> {
+    let info = config.connection_info()?;
+    if !is_tls(&info) {
+        return Ok(redis::Client::open(info)?);
+    }
+    Ok(redis::Client::build_with_tls(
+        info,
+        tls_certificates(config)?,
+    )?)
+}
+
+/// Make a client which asks the sentinels for the current master each time it connects, so that
+/// reconnecting follows a failover
+fn sentinel_client(
+    config: &RedisConfig,
+    sentinel: &SentinelConfig,
+) -> Result<SentinelClient, Box<dyn Error>> {
+    let info = config.connection_info()?;
+    let mut sentinels = vec![info.addr.clone()];
+    for node in &sentinel.nodes {
+        sentinels.push(node.as_str().into_connection_info()?.addr);
+    }
+    let mut builder = SentinelClientBuilder::new(
+        sentinels,
+        sentinel.master_name.clone(),
+        SentinelServerType::Master,
+    )?
+    .set_client_to_redis_db(info.redis.db);
+    if let Some(username) = &info.redis.username {
+        builder = builder.set_client_to_redis_username(username.clone());
+    }
+    if let Some(password) = &info.redis.password {
+        builder = builder.set_client_to_redis_password(password.clone());
+    }
+    if let Some(username) = &sentinel.username {
+        builder = builder.set_client_to_sentinel_username(username.clone());
+    }
+    if let Some(password) = &sentinel.password {
+        builder = builder.set_client_to_sentinel_password(password.expose().to_owned());
+    }
+    if is_tls(&info) {
+        let certs = tls_certificates(config)?;
+        builder = builder
+            .set_client_to_redis_tls_mode(redis::TlsMode::Secure)
+            .set_client_to_redis_certificates(certs.clone())
+            .set_client_to_sentinel_certificates(certs);
+    }
+    Ok(builder.build()?)
+}
+
+/// Make a client for a Redis Cluster, which routes each command to the node with its key's slot
+fn cluster_client(
+    config: &RedisConfig,
+    cluster: &ClusterConfig,
+) -> Result<ClusterClient, Box<dyn Error>> {
+    let info = config.connection_info()?;
+    let mut nodes = vec![info.clone()];
+    for node in &cluster.nodes {
+        let mut node = node.as_str().into_connection_info()?;
+        node.redis = info.redis.clone();
+        nodes.push(node);
+    }
+    let mut builder = ClusterClient::builder(nodes);
+    if is_tls(&info) {
+        builder = builder
+            .tls(redis::TlsMode::Secure)
+            .certs(tls_certificates(config)?);
+    }
+    Ok(builder.build()?)
+}
+
+/// A connection to a single Redis server, found through Sentinel or not, or to a Redis Cluster
+#[derive(Clone)]
+enum RedisConn {
+    Single(MultiplexedConnection),
+    Cluster(ClusterConnection),
+}
+
+impl ConnectionLike for RedisConn {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a redis::Cmd) -> RedisFuture<'a, redis::Value> {
+        match self {
+            RedisConn::Single(conn) => conn.req_packed_command(cmd),
+            RedisConn::Cluster(conn) => conn.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a redis::Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<redis::Value>> {
+        match self {
+            RedisConn::Single(conn) => conn.req_packed_commands(cmd, offset, count),
+            RedisConn::Cluster(conn) => conn.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            RedisConn::Single(conn) => conn.get_db(),
+            RedisConn::Cluster(conn) => conn.get_db(),
+        }
+    }
+}
+
+/// A client for whichever kind of Redis deployment is configured
+enum RedisClient {
+    Single(redis::Client),
+    Sentinel(SentinelClient),
+    Cluster(ClusterClient),
+}
+
+fn client(config: &RedisConfig) -> Result<RedisClient, Box<dyn Error>> {
+    if let Some(sentinel) = &config.sentinel {
+        return Ok(RedisClient::Sentinel(sentinel_client(config, sentinel)?));
+    }
+    if let Some(cluster) = &config.cluster {
+        return Ok(RedisClient::Cluster(cluster_client(config, cluster)?));
+    }
+    Ok(RedisClient::Single(redis_client(config)?))
+}
+
+async fn redis_conn(config: &RedisConfig) -> Result<RedisConn, redis::RedisError> {
+    let client = client(config).map_err(|e| {
+        redis::RedisError::from((
+            redis::ErrorKind::InvalidClientConfig,
+            "Invalid Redis config",
+            e.to_string(),
+        ))
+    })?;
+    Ok(match client {
+        RedisClient::Single(client) => {
+            RedisConn::Single(client.get_multiplexed_async_connection().await?)
+        }
+        RedisClient::Sentinel(mut sentinel) => {
+            let master = sentinel.async_get_client().await?;
+            RedisConn::Single(master.get_multiplexed_async_connection().await?)
+        }
+        RedisClient::Cluster(client) => RedisConn::Cluster(client.get_async_connection().await?),
+    })
 }
 
 fn stream_read_opts(config: &RedisConfig) -> redis::streams::StreamReadOptions {
     redis::streams::StreamReadOptions::default()
         .count(config.chunk_size.into())
         .block(config.blocktime_millis)
-        .group("log-ingestor", "log-ingestor")
+        .group(&config.consumer_group, &config.consumer_id)
 }
 
-/// Fetch unread logs for redis.
-/// Returns a tuple of the last ID read and a Vec of msgpacked entries from the log stream endpoint
-fn read_logs(
-    redis_conn: &mut redis::Connection,
-    last_id: &String,
-    config: &RedisConfig,
-) -> Result<(Option<String>, Vec<redis::Value>), Box<dyn Error>> {
-    let raw_reply: redis::streams::StreamReadReply =
-        redis_conn.xread_options(&LOGGING_ENDPOINT, &[last_id], &stream_read_opts(config))?;
-
-    let log_key = raw_reply
-        .keys
-        .get(0)
-        .ok_or_else(|| str_error(KEY_MISMATCH))?;
-
-    let last_id = log_key.ids.last().map(|i| i.id.clone());
-    let logs = log_key
-        .ids
+/// IDs of stream entries and their msgpacked data, in the same order
+type Entries = (Vec<String>, Vec<redis::Value>);
+
+/// Split stream entries into their IDs and their msgpacked data. Entries without data (e.g. pending
+/// entries which have since been deleted from the stream) get a Nil value, which fails to decode.
+fn split_entries(entries: &[redis::streams::StreamId]) -> Entries {
+    entries
         .iter()
-        .map(|e| e.map.get("data").ok_or_else(|| str_error(NO_DATA)).cloned())
-        .collect::<Result<Vec<redis::Value>, Box<dyn Error>>>()?;
+        .map(|e| {
+            let data = e.map.get("data").cloned().unwrap_or(redis::Value::Nil);
+            (e.id.clone(), data)
+        })
+        .unzip()
+}
 
-    Ok((last_id, logs))
+/// The name of each stream in a read reply, along with its IDs and msgpacked entries. No keys at
+/// all means a blocking read timed out without any new entries.
+fn reply_entries(keys: &[redis::streams::StreamKey]) -> Vec<(String, Entries)> {
+    keys.iter()
+        .map(|key| (key.key.clone(), split_entries(&key.ids)))
+        .collect()
 }
 
-fn process_data(values: Vec<redis::Value>) -> Result<Vec<LogMessagePack>, Box<dyn Error>> {
-    let un_valued: Vec<Vec<u8>> = values
-        .iter()
-        .map(|e| match e {
-            redis::Value::BulkString(x) => Ok(x.iter().cloned().collect()),
-            _ => Err(str_error("Log message data not binary-data!")),
+/// Fetch logs for redis from the given streams, each starting after the corresponding ID in
+/// last_ids. Use ">" for new entries or an explicit ID to read back this consumer's pending
+/// entries. On a Redis Cluster the streams have to be in the same hash slot.
+/// Returns the name of each stream which had entries, along with its IDs and msgpacked entries
+async fn read_logs(
+    redis_conn: &mut RedisConn,
+    streams: &[&str],
+    last_ids: &[&str],
+    config: &RedisConfig,
+) -> Result<Vec<(String, Entries)>, Box<dyn Error>> {
+    let raw_reply: redis::streams::StreamReadReply = redis_conn
+        .xread_options(streams, last_ids, &stream_read_opts(config))
+        .await?;
+    Ok(reply_entries(&raw_reply.keys))
+}
+
+/// Blocking reads of new entries from the hash slots of a Redis Cluster, which may each be on a
+/// different node. A read still waiting when another returns is kept for the next call rather
+/// than dropped, since Redis could deliver entries to it at any time.
+#[derive(Default)]
+struct SlotReads {
+    reads: tokio::task::JoinSet<redis::RedisResult<redis::streams::StreamReadReply>>,
+    /// The streams each read is for
+    streams: HashMap<tokio::task::Id, Vec<String>>,
+}
+
+impl SlotReads {
+    /// Start a read for each slot which isn't being read already, then wait for the first one to
+    /// return. Any others which have returned by then are passed on along with it.
+    async fn next(
+        &mut self,
+        redis_conn: &RedisConn,
+        streams: &[&str],
+        config: &RedisConfig,
+    ) -> Result<Vec<(String, Entries)>, Box<dyn Error>> {
+        let new_ids = vec![">"; streams.len()];
+        for (group, _) in slot_groups(streams, &new_ids) {
+            let group: Vec<String> = group.iter().map(|s| s.to_string()).collect();
+            if self.streams.values().any(|s| *s == group) {
+                continue;
+            }
+            let mut conn = redis_conn.clone();
+            let opts = stream_read_opts(config);
+            let ids = vec![">"; group.len()];
+            let streams = group.clone();
+            let task = self
+                .reads
+                .spawn(async move { conn.xread_options(&streams, &ids, &opts).await });
+            self.streams.insert(task.id(), group);
+        }
+
+        let mut done = vec![];
+        if let Some(read) = self.reads.join_next_with_id().await {
+            done.push(read);
+        }
+        while let Some(read) = self.reads.try_join_next_with_id() {
+            done.push(read);
+        }
+        let mut keys = vec![];
+        let mut error: Option<Box<dyn Error>> = None;
+        for read in done {
+            let (id, read) = match read {
+                Ok((id, read)) => (id, read.map_err(Box::from)),
+                Err(join_error) => (join_error.id(), Err(join_error.into())),
+            };
+            self.streams.remove(&id);
+            match read {
+                Ok(reply) => keys.extend(reply_entries(&reply.keys)),
+                Err(read_error) => error = Some(read_error),
+            }
+        }
+        // Entries which were read are passed on even if another read failed, as they are already
+        // pending on this consumer
+        match error {
+            Some(error) if keys.is_empty() => Err(error),
+            _ => Ok(keys),
+        }
+    }
+}
+
+/// Fetch new logs from the given streams. Streams in different hash slots of a Redis Cluster are
+/// read at once, and whatever any of them returns first is passed on straight away.
+async fn read_new_logs(
+    redis_conn: &mut RedisConn,
+    slot_reads: &mut SlotReads,
+    streams: &[&str],
+    config: &RedisConfig,
+) -> Result<Vec<(String, Entries)>, Box<dyn Error>> {
+    let new_ids = vec![">"; streams.len()];
+    match redis_conn {
+        RedisConn::Cluster(_) if slot_groups(streams, &new_ids).len() > 1 => {
+            slot_reads.next(redis_conn, streams, config).await
+        }
+        _ => read_logs(redis_conn, streams, &new_ids, config).await,
+    }
+}
+
+/// Number of hash slots in a Redis Cluster
+const CLUSTER_SLOTS: u16 = 16384;
+
+/// CRC16/XMODEM, which Redis Cluster hashes keys with
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ (u16::from(byte) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
         })
-        .collect::<Result<Vec<Vec<u8>>, Box<dyn Error>>>()?;
+    })
+}
 
-    Ok(un_valued
-        .iter()
-        .map(|e| rmp_serde::from_slice::<LogMessagePack>(e))
-        .collect::<Result<Vec<LogMessagePack>, rmp_serde::decode::Error>>()?)
+/// The hash slot of a key in a Redis Cluster. Only the part in the first `{...}` is hashed if
+/// there is one and it isn't empty, so that related keys can share a slot.
+fn key_slot(key: &[u8]) -> u16 {
+    let tag = key.iter().position(|&b| b == b'{').and_then(|start| {
+        let len = key[start + 1..].iter().position(|&b| b == b'}')?;
+        (len > 0).then(|| &key[start + 1..start + 1 + len])
+    });
+    crc16(tag.unwrap_or(key)) % CLUSTER_SLOTS
 }
 
-fn extract_records(messages: Vec<LogMessagePack>) -> Vec<LogMsg> {
-    messages
-        .iter()
-        .map(|e| e.bec_codec.data.log_msg.clone())
+/// Group streams and their IDs by hash slot, since a Redis Cluster can only read streams in the
+/// same slot together
+fn slot_groups<'a>(streams: &[&'a str], last_ids: &[&'a str]) -> Vec<(Vec<&'a str>, Vec<&'a str>)> {
+    let mut groups: Vec<(u16, Vec<&str>, Vec<&str>)> = vec![];
+    for (stream, id) in streams.iter().zip(last_ids) {
+        let slot = key_slot(stream.as_bytes());
+        match groups.iter_mut().find(|(s, _, _)| *s == slot) {
+            Some((_, streams, ids)) => {
+                streams.push(stream);
+                ids.push(id);
+            }
+            None => groups.push((slot, vec![stream], vec![id])),
+        }
+    }
+    groups
+        .into_iter()
+        .map(|(_, streams, ids)| (streams, ids))
         .collect()
 }
 
-fn setup_consumer_group(conn: &mut redis::Connection, config: &RedisConfig) {
-    let group: Result<(), redis::RedisError> =
-        conn.xgroup_create(&LOGGING_ENDPOINT, &config.consumer_group, "0");
+/// Claim entries from a stream which have been pending for longer than the configured idle time,
+/// e.g. because the consumer they were delivered to died, starting from start_id.
+/// Returns the ID to continue claiming from, which is "0-0" once the whole pending list has been
+/// scanned, and the IDs and msgpacked entries claimed.
+async fn claim_logs(
+    redis_conn: &mut RedisConn,
+    stream: &str,
+    start_id: &str,
+    config: &RedisConfig,
+) -> Result<(String, Entries), Box<dyn Error>> {
+    let reply: redis::streams::StreamAutoClaimReply = redis_conn
+        .xautoclaim_options(
+            stream,
+            &config.consumer_group,
+            &config.consumer_id,
+            config.claim_min_idle_millis,
+            start_id,
+            redis::streams::StreamAutoClaimOptions::default().count(config.chunk_size.into()),
+        )
+        .await?;
+    Ok((reply.next_stream_id, split_entries(&reply.claimed)))
+}
+
+fn entry_bytes(value: &redis::Value) -> Result<&[u8], Box<dyn Error>> {
+    match value {
+        redis::Value::BulkString(x) => Ok(x),
+        _ => Err(str_error(NOT_BINARY)),
+    }
+}
+
+/// The LogMsg in a decoded BEC LogMessage
+fn log_msg(message: bec_codec::BecMessage) -> Result<LogMsg, Box<dyn Error>> {
+    if message.type_name != LOG_MESSAGE {
+        return Err(format!("Expected a {LOG_MESSAGE}, got {}", message.type_name).into());
+    }
+    let mut data = message.data;
+    let fields = data.as_object_mut().ok_or("LogMessage data is not a map")?;
+    let log_msg = fields
+        .remove("log_msg")
+        .ok_or("LogMessage without log_msg")?;
+    let mut msg = match log_msg {
+        serde_json::Value::String(text) => LogMsg::from_text(text),
+        log_msg => LogMsg {
+            schema_version: RECORD_SCHEMA,
+            ..serde_json::from_value(log_msg)?
+        },
+    };
+    // The level is only in the record, so fall back to the message's log_type for older releases
+    if let Some(log_type) = fields.get("log_type").and_then(|t| t.as_str())
+        && msg.record.level.name.is_empty()
+    {
+        msg.record.level.name = log_type.to_uppercase();
+    }
+    Ok(msg)
+}
+
+/// Decode a single entry with its stream's decoder
+fn decode_entry(decoder: Decoder, value: &redis::Value) -> Result<Payload, Box<dyn Error>> {
+    let bytes = entry_bytes(value)?;
+    Ok(match decoder {
+        Decoder::BecLog => Payload::Log(log_msg(bec_codec::decode_message(bytes)?)?.into()),
+        Decoder::BecMessage => {
+            let message = bec_codec::decode_message(bytes)?;
+            if message.type_name == LOG_MESSAGE {
+                Payload::Log(log_msg(message)?.into())
+            } else {
+                let mut data = message.data;
+                if let serde_json::Value::Object(fields) = &mut data {
+                    fields.insert("bec_message_type".into(), message.type_name.into());
+                }
+                Payload::Value(data)
+            }
+        }
+        Decoder::Msgpack => Payload::Value(bec_codec::decode_value(bytes)?),
+    })
+}
+
+async fn setup_consumer_group(
+    conn: &mut RedisConn,
+    stream: &str,
+    config: &RedisConfig,
+) -> Result<(), redis::RedisError> {
+    let group: Result<(), redis::RedisError> = conn
+        .xgroup_create_mkstream(stream, &config.consumer_group, "0")
+        .await;
     match group {
         Ok(_) => (),
         Err(error) => {
@@ -213,46 +720,517 @@ fn setup_consumer_group(conn: &mut redis::Connection, config: &RedisConfig) {
                 && code == "BUSYGROUP"
             {
                 println!(
-                    "Group {} already exists, rejoining with ID {}",
+                    "Group {} already exists on {stream}, rejoining with ID {}",
                     &config.consumer_group, &config.consumer_id
                 )
             } else {
-                panic!(
-                    "Failed to create Redis consumer group {}!",
+                println!(
+                    "Failed to create Redis consumer group {} on {stream}!",
                     &config.consumer_group
                 );
+                return Err(error);
+            }
+        }
+    }
+    let create_id: Result<(), redis::RedisError> = conn
+        .xgroup_createconsumer(stream, &config.consumer_group, &config.consumer_id)
+        .await;
+    create_id.inspect_err(|_| {
+        println!(
+            "Failed to create Redis consumer ID {} in group {} on {stream}!",
+            &config.consumer_id, &config.consumer_group
+        )
+    })
+}
+
+/// Whether an error means the connection has to be re-established, including the consumer group
+/// having vanished because Redis was restarted without persistence
+fn is_connection_error(error: &redis::RedisError) -> bool {
+    error.is_io_error()
+        || error.is_connection_dropped()
+        || error.is_unrecoverable_error()
+        || matches!(error.retry_method(), redis::RetryMethod::Reconnect)
+        || error.code() == Some("NOGROUP")
+        // Writing to a master which Sentinel has since demoted to a replica
+        || error.code() == Some("READONLY")
+}
+
+fn needs_reconnect(error: &(dyn Error + 'static)) -> bool {
+    error
+        .downcast_ref::<redis::RedisError>()
+        .is_some_and(is_connection_error)
+}
+
+async fn try_connect(config: &RedisConfig) -> Result<RedisConn, redis::RedisError> {
+    let mut conn = redis_conn(config).await?;
+    for stream in &config.streams {
+        setup_consumer_group(&mut conn, &stream.name, config).await?;
+    }
+    Ok(conn)
+}
+
+/// Connect to Redis and (re)join the consumer group, retrying with exponential backoff until it
+/// succeeds so that the ingestor can ride through Redis restarts
+async fn connect(config: &RedisConfig, role: &str) -> RedisConn {
+    let mut backoff = Backoff::new(&config.reconnect);
+    loop {
+        let error = match try_connect(config).await {
+            Ok(conn) => {
+                println!(
+                    "Redis {role}: connected to {} (attempt {})",
+                    config
+                        .connection_info()
+                        .map(|info| info.addr.to_string())
+                        .unwrap_or_default(),
+                    backoff.attempt() + 1
+                );
+                return conn;
+            }
+            Err(error) => error,
+        };
+        let delay = backoff.next_delay();
+        println!(
+            "Redis {role}: connection attempt {} failed ({error}), retrying in {delay:?}",
+            backoff.attempt()
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Write an entry which could not be decoded to the dead-letter stream, with enough context to
+/// investigate or replay it
+async fn dead_letter(
+    conn: &mut RedisConn,
+    entry: &EntryId,
+    value: &redis::Value,
+    error: &str,
+    config: &RedisConfig,
+) -> Result<(), redis::RedisError> {
+    let raw = match value {
+        redis::Value::BulkString(bytes) => bytes.clone(),
+        other => format!("{other:?}").into_bytes(),
+    };
+    let fields: [(&str, Vec<u8>); 5] = [
+        ("raw", raw),
+        ("stream", entry.stream.clone().into_bytes()),
+        ("id", entry.id.clone().into_bytes()),
+        ("error", error.as_bytes().to_vec()),
+        ("timestamp", chrono::Utc::now().to_rfc3339().into_bytes()),
+    ];
+    let _: Option<String> = conn
+        .xadd_maxlen(
+            &config.dead_letter_stream,
+            redis::streams::StreamMaxlen::Approx(config.dead_letter_max_len),
+            "*",
+            &fields,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Decode entries from one stream and pass them on to the consumer.
+/// Each entry is decoded on its own. Entries which fail are written to the dead-letter stream and
+/// replaced by a document describing the failure, which still gets the entry acknowledged once
+/// it is written. If the dead-letter write fails too, the entry is left pending to be claimed and
+/// retried later.
+async fn forward(
+    conn: &mut RedisConn,
+    tx: &mpsc::Sender<StreamMsg>,
+    in_flight: &InFlight,
+    stream: &StreamConfig,
+    ids: Vec<String>,
+    packed: Vec<redis::Value>,
+    config: &RedisConfig,
+) -> Result<(), Box<dyn Error>> {
+    for (id, value) in ids.into_iter().zip(packed) {
+        let entry = EntryId {
+            stream: stream.name.clone(),
+            id,
+        };
+        let decoded = decode_entry(stream.decoder, &value).map_err(|e| e.to_string());
+        let payload = match decoded {
+            Ok(payload) => payload,
+            Err(error) => {
+                let failures = DECODE_FAILURES.fetch_add(1, Ordering::Relaxed) + 1;
+                println!(
+                    "Failed to decode entry {} from {} ({failures} so far): {error}",
+                    entry.id, entry.stream
+                );
+                if let Err(dl_error) = dead_letter(conn, &entry, &value, &error, config).await {
+                    println!(
+                        "Failed to write entry {} to dead-letter stream {}: {dl_error}",
+                        entry.id, config.dead_letter_stream
+                    );
+                    continue;
+                }
+                Payload::Log(decode_error_msg(&entry, &error).into())
+            }
+        };
+        in_flight.insert(entry.clone());
+        let msg = StreamMsg {
+            entry,
+            index: stream.index.clone(),
+            payload,
+        };
+        tx.send(msg)
+            .await
+            .map_err(|_| str_error(RECEIVER_DROPPED))?;
+    }
+    Ok(())
+}
+
+/// Whether a consumer has been idle for long enough, and has nothing left pending, so that it can be
+/// removed from the group. Consumer IDs based on the hostname and PID change with every restart,
+/// so without this the group would accumulate dead consumers.
+fn is_stale(consumer: &redis::streams::StreamInfoConsumer, config: &RedisConfig) -> bool {
+    consumer.name != config.consumer_id
+        && consumer.pending == 0
+        && consumer.idle >= config.stale_consumer_idle_millis
+}
+
+/// Remove stale consumers from the group on a stream. This runs after claiming idle entries, which
+/// moves any pending entries off dead consumers.
+async fn remove_stale_consumers(
+    redis_conn: &mut RedisConn,
+    stream: &str,
+    config: &RedisConfig,
+) -> Result<(), redis::RedisError> {
+    let reply: redis::streams::StreamInfoConsumersReply = redis_conn
+        .xinfo_consumers(stream, &config.consumer_group)
+        .await?;
+    for consumer in reply.consumers.iter().filter(|c| is_stale(c, config)) {
+        let _: usize = redis_conn
+            .xgroup_delconsumer(stream, &config.consumer_group, &consumer.name)
+            .await?;
+        println!(
+            "Removed stale consumer {} from group {} on {stream}",
+            consumer.name, config.consumer_group
+        );
+    }
+    Ok(())
+}
+
+/// Re-deliver entries which were read by this consumer before a restart but never acknowledged
+async fn recover_pending(
+    redis_conn: &mut RedisConn,
+    tx: &mpsc::Sender<StreamMsg>,
+    in_flight: &InFlight,
+    stream: &StreamConfig,
+    config: &RedisConfig,
+) -> Result<(), Box<dyn Error>> {
+    let mut last_id: String = "0".into();
+    let mut recovered = 0;
+    loop {
+        let read = read_logs(redis_conn, &[&stream.name], &[&last_id], config)
+            .await
+            .map_err(|e| e.to_string());
+        match read {
+            Ok(mut keys) if keys.iter().any(|(_, (ids, _))| !ids.is_empty()) => {
+                let (_, (ids, packed)) = keys.remove(0);
+                recovered += ids.len();
+                last_id = ids.last().cloned().unwrap_or_default();
+                forward(redis_conn, tx, in_flight, stream, ids, packed, config).await?;
+            }
+            Ok(_) => break,
+            Err(error) => {
+                println!(
+                    "Failed to read pending entries from {}: {error}",
+                    stream.name
+                );
+                break;
             }
         }
     }
-    let create_id: Result<(), redis::RedisError> = conn.xgroup_createconsumer(
-        &LOGGING_ENDPOINT,
-        &config.consumer_group,
-        &config.consumer_id,
+    if recovered > 0 {
+        println!("Recovered {recovered} pending entries from {}", stream.name);
+    }
+    Ok(())
+}
+
+/// Take over entries left pending by other consumers in the group for longer than the idle time.
+/// Entries this consumer still has in flight are skipped rather than sent again.
+async fn claim_idle(
+    redis_conn: &mut RedisConn,
+    tx: &mpsc::Sender<StreamMsg>,
+    in_flight: &InFlight,
+    stream: &StreamConfig,
+    config: &RedisConfig,
+) -> Result<(), Box<dyn Error>> {
+    let mut start_id: String = "0-0".into();
+    let mut claimed = 0;
+    loop {
+        let claim = claim_logs(redis_conn, &stream.name, &start_id, config)
+            .await
+            .map_err(|e| e.to_string());
+        match claim {
+            Ok((next_id, (ids, packed))) => {
+                let (ids, packed): (Vec<String>, Vec<redis::Value>) = ids
+                    .into_iter()
+                    .zip(packed)
+                    .filter(|(id, _)| {
+                        !in_flight.contains(&EntryId {
+                            stream: stream.name.clone(),
+                            id: id.clone(),
+                        })
+                    })
+                    .unzip();
+                claimed += ids.len();
+                if !ids.is_empty() {
+                    forward(redis_conn, tx, in_flight, stream, ids, packed, config).await?;
+                }
+                if next_id == "0-0" {
+                    break;
+                }
+                start_id = next_id;
+            }
+            Err(error) => {
+                println!("Failed to claim idle entries from {}: {error}", stream.name);
+                break;
+            }
+        }
+    }
+    if claimed > 0 {
+        println!("Claimed {claimed} idle entries from {}", stream.name);
+    }
+    Ok(())
+}
+
+/// Wait until there is room in the queue for everything a read could return, so that entries stay
+/// in Redis rather than piling up in memory while Elastic is slow or down.
+/// Returns false if the consumer has gone away.
+async fn wait_for_capacity(tx: &mpsc::Sender<StreamMsg>, wanted: usize) -> bool {
+    let wanted = wanted.min(tx.max_capacity());
+    if tx.is_closed() {
+        return false;
+    }
+    if tx.capacity() >= wanted {
+        return true;
+    }
+    println!(
+        "Redis producer: queue full ({} of {} queued), pausing reads",
+        tx.max_capacity() - tx.capacity(),
+        tx.max_capacity()
     );
-    create_id.expect(&format!(
-        "Failed to create Redis consumer ID {} in group {}!",
-        &config.consumer_id, &config.consumer_group
-    ));
+    // Only this task sends, so the capacity can't be taken by anyone else once the permits are
+    // released again
+    let Ok(permits) = tx.reserve_many(wanted).await else {
+        return false;
+    };
+    drop(permits);
+    println!("Redis producer: queue has room again, resuming reads");
+    true
 }
 
-pub async fn producer_loop(tx: mpsc::UnboundedSender<LogMsg>, config: RedisConfig) {
-    let mut redis_conn = redis_conn(&config.url.full_url()).expect("Could not connect to Redis!");
-    let stream_read_id: String = ">".into();
-    setup_consumer_group(&mut redis_conn, &config);
+pub async fn producer_loop(
+    tx: mpsc::Sender<StreamMsg>,
+    in_flight: Arc<InFlight>,
+    config: RedisConfig,
+) {
+    let mut redis_conn = connect(&config, "producer").await;
+    let stream_names: Vec<&str> = config.streams.iter().map(|s| s.name.as_str()).collect();
+    let mut slot_reads = SlotReads::default();
 
-    'main: loop {
-        if let Ok((Some(_), packed)) = read_logs(&mut redis_conn, &stream_read_id, &config) {
-            let unpacked = process_data(packed).unwrap_or(vec![error_log_item()]);
-            let records = extract_records(unpacked);
+    let claim_interval = Duration::from_millis(config.claim_interval_millis);
+    for stream in &config.streams {
+        if let Err(error) = recover_pending(&mut redis_conn, &tx, &in_flight, stream, &config).await
+        {
+            println!("{error}");
+            return;
+        }
+    }
+    // Claim straight away on startup, then periodically
+    let mut last_claim: Option<Instant> = None;
+    // Slows down retries for errors which a new connection won't fix
+    let mut backoff = Backoff::new(&config.reconnect);
 
-            for record in records {
-                if tx.send(record).is_err() {
-                    println!("Receiver dropped, stopping...");
+    'main: loop {
+        if last_claim.is_none_or(|t| t.elapsed() >= claim_interval) {
+            last_claim = Some(Instant::now());
+            for stream in &config.streams {
+                if claim_idle(&mut redis_conn, &tx, &in_flight, stream, &config)
+                    .await
+                    .is_err()
+                {
                     break 'main;
                 }
+                if let Err(error) =
+                    remove_stale_consumers(&mut redis_conn, &stream.name, &config).await
+                {
+                    println!(
+                        "Failed to remove stale consumers from {}: {error}",
+                        stream.name
+                    );
+                }
             }
         }
+        if !wait_for_capacity(&tx, usize::from(config.chunk_size) * stream_names.len()).await {
+            break;
+        }
+        // Don't sit out the rest of a blocking read if there is nobody left to send to
+        let read = tokio::select! {
+            _ = tx.closed() => break,
+            read = read_new_logs(&mut redis_conn, &mut slot_reads, &stream_names, &config) => {
+                // The error isn't Send, so can't be held on to across the awaits below
+                read.map_err(|e| (e.to_string(), needs_reconnect(e.as_ref())))
+            }
+        };
+        let reconnect = match read {
+            Ok(keys) => {
+                backoff.reset();
+                for (name, (ids, packed)) in keys {
+                    let Some(stream) = config.stream(&name) else {
+                        println!("Redis producer: got entries for unknown stream {name}");
+                        continue;
+                    };
+                    if !ids.is_empty()
+                        && forward(
+                            &mut redis_conn,
+                            &tx,
+                            &in_flight,
+                            stream,
+                            ids,
+                            packed,
+                            &config,
+                        )
+                        .await
+                        .is_err()
+                    {
+                        break 'main;
+                    }
+                }
+                continue;
+            }
+            Err((error, reconnect)) => {
+                println!("Redis producer: failed to read logs ({error})");
+                reconnect
+            }
+        };
+        if reconnect {
+            println!("Redis producer: connection lost, reconnecting");
+            redis_conn = connect(&config, "producer").await;
+        } else {
+            tokio::time::sleep(backoff.next_delay()).await;
+        }
     }
+    println!("{RECEIVER_DROPPED}");
+}
+
+/// Acknowledge stream entries once Elastic has confirmed that they were written
+async fn ack_entries(
+    conn: &mut RedisConn,
+    stream: &str,
+    ids: &[&str],
+    config: &RedisConfig,
+) -> Result<usize, redis::RedisError> {
+    conn.xack(stream, &config.consumer_group, ids).await
+}
+
+/// Reset the idle time of entries this consumer still has in flight, so that no consumer in the
+/// group takes them for abandoned and claims them while they are waiting on Elastic
+async fn refresh_in_flight(
+    conn: &mut RedisConn,
+    stream: &str,
+    ids: &[String],
+    config: &RedisConfig,
+) -> Result<(), redis::RedisError> {
+    redis::cmd("XCLAIM")
+        .arg(stream)
+        .arg(&config.consumer_group)
+        .arg(&config.consumer_id)
+        .arg(0)
+        .arg(ids)
+        .arg("JUSTID")
+        .exec_async(conn)
+        .await
+}
+
+/// How often entries in flight are refreshed, well within the time after which they could be
+/// claimed
+fn refresh_interval(config: &RedisConfig) -> Duration {
+    Duration::from_millis((config.claim_min_idle_millis / 3).max(1) as u64)
+}
+
+async fn ack(
+    redis_conn: &mut RedisConn,
+    entries: &[EntryId],
+    in_flight: &InFlight,
+    config: &RedisConfig,
+) {
+    for stream in &config.streams {
+        let ids: Vec<&str> = entries
+            .iter()
+            .filter(|e| e.stream == stream.name)
+            .map(|e| e.id.as_str())
+            .collect();
+        if ids.is_empty() {
+            continue;
+        }
+        loop {
+            match ack_entries(redis_conn, &stream.name, &ids, config).await {
+                Ok(count) => println!(
+                    "Acknowledged {count} of {} entries on {}",
+                    ids.len(),
+                    stream.name
+                ),
+                Err(error) if is_connection_error(&error) => {
+                    println!("Redis acknowledger: connection lost ({error}), reconnecting");
+                    *redis_conn = connect(config, "acknowledger").await;
+                    continue;
+                }
+                Err(error) => println!(
+                    "Failed to acknowledge {} entries on {}: {error}",
+                    ids.len(),
+                    stream.name
+                ),
+            }
+            break;
+        }
+    }
+    in_flight.release(entries);
+}
+
+async fn refresh(redis_conn: &mut RedisConn, in_flight: &InFlight, config: &RedisConfig) {
+    for stream in &config.streams {
+        let ids = in_flight.ids(&stream.name);
+        if ids.is_empty() {
+            continue;
+        }
+        if let Err(error) = refresh_in_flight(redis_conn, &stream.name, &ids, config).await {
+            println!(
+                "Failed to refresh {} entries in flight on {}: {error}",
+                ids.len(),
+                stream.name
+            );
+            if is_connection_error(&error) {
+                *redis_conn = connect(config, "acknowledger").await;
+            }
+        }
+    }
+}
+
+pub async fn ack_loop(
+    mut rx: mpsc::UnboundedReceiver<Vec<EntryId>>,
+    in_flight: Arc<InFlight>,
+    config: RedisConfig,
+) {
+    // The producer's connection spends most of its time in a blocking XREADGROUP, which would hold
+    // up anything multiplexed behind it, so acknowledgements get their own connection
+    let mut redis_conn = connect(&config, "acknowledger").await;
+    let mut refresh_tick = tokio::time::interval(refresh_interval(&config));
+    refresh_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            entries = rx.recv() => {
+                let Some(entries) = entries else {
+                    break;
+                };
+                ack(&mut redis_conn, &entries, &in_flight, &config).await;
+            }
+            _ = refresh_tick.tick() => refresh(&mut redis_conn, &in_flight, &config).await,
+        }
+    }
+    println!("Consumer dropped, acknowledger exiting");
 }
 
 #[cfg(test)]
@@ -260,57 +1238,407 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_error_log_item_contents() {
-        let err_item = error_log_item();
-        assert_eq!(err_item.bec_codec.data.log_msg.record.level.name, "ERROR");
+    fn test_redis_client_tls() {
+        let (cert, key) = crate::tls::tests::self_signed();
+        let mut pem = cert.to_pem().unwrap();
+        pem.extend(key.private_key_to_pem_pkcs8().unwrap());
+        let pem = crate::tls::tests::write_file("redis-client.pem", &pem);
+        let config = |tls: String| {
+            let config: RedisConfig = toml::from_str(&format!(
+                "url = {{ url = \"rediss://localhost\", port = 6380 }}\ntls = {{ {tls} }}"
+            ))
+            .unwrap();
+            redis_client(&config).map_err(|e| e.to_string())
+        };
+        let pem = pem.display();
+        assert!(config(format!("ca_file = \"{pem}\", client_cert = \"{pem}\"")).is_ok());
+        assert!(config("ca_file = \"/nonexistent.pem\"".into()).is_err());
+        let bad = crate::tls::tests::write_file("redis-bad.pem", b"not a certificate");
+        let bad = bad.display();
+        assert!(config(format!("ca_file = \"{pem}\", client_cert = \"{bad}\"")).is_err());
+    }
+
+    #[test]
+    fn test_client_kinds() {
+        let client_for = |extra: &str| {
+            let config: RedisConfig = toml::from_str(&format!(
+                "url = {{ url = \"redis://localhost\", port = 26379 }}\n{extra}"
+            ))
+            .unwrap();
+            client(&config)
+        };
+        assert!(matches!(client_for(""), Ok(RedisClient::Single(_))));
+        let sentinel = "[sentinel]\nmaster_name = \"bec\"\nnodes = [\"redis://other:26379\"]";
+        assert!(matches!(client_for(sentinel), Ok(RedisClient::Sentinel(_))));
+        let cluster = "[cluster]\nnodes = [\"redis://other:6379\"]";
+        assert!(matches!(client_for(cluster), Ok(RedisClient::Cluster(_))));
+        assert!(client_for("[cluster]\nnodes = [\"not a url\"]").is_err());
+    }
+
+    #[test]
+    fn test_key_slot() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        // As given by CLUSTER KEYSLOT
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"somekey"), 11058);
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
         assert_eq!(
-            err_item.bec_codec.data.log_msg.record.message,
-            "Error processing log messages from Redis!"
+            key_slot(b"foo{}{bar}"),
+            crc16(b"foo{}{bar}") % CLUSTER_SLOTS
         );
+        assert_eq!(key_slot(b"foo{{bar}}zap"), key_slot(b"{bar"));
     }
 
     #[test]
-    fn test_extract_records() {
-        let mut pack = error_log_item();
-        pack.bec_codec.data.log_msg.record.message = "test".to_string();
-        let records = extract_records(vec![pack.clone()]);
-        assert_eq!(records.len(), 1);
-        assert_eq!(records[0].record.message, "test");
+    fn test_slot_groups() {
+        let groups = slot_groups(&["{bec}info/log", "scans", "{bec}alarms"], &[">", "0", ">"]);
+        assert_eq!(
+            groups,
+            vec![
+                (vec!["{bec}info/log", "{bec}alarms"], vec![">", ">"]),
+                (vec!["scans"], vec!["0"]),
+            ]
+        );
     }
 
     #[test]
-    fn test_process_data_valid() {
-        let pack = error_log_item();
-        let bytes = rmp_serde::to_vec(&pack).unwrap();
-        let redis_val = redis::Value::BulkString(bytes.into());
-        let result = process_data(vec![redis_val]);
-        assert!(result.is_ok());
-        let unpacked = result.unwrap();
-        assert_eq!(unpacked.len(), 1);
+    fn test_timestamp() {
+        let time = Timestamp {
+            repr: "2024-03-05 13:00:00.123456+01:00".into(),
+            timestamp: Some(1_709_640_000.123456),
+            ..Default::default()
+        };
+        assert_eq!(time.as_rfc3339().unwrap(), "2024-03-05T12:00:00.123456Z");
+        assert_eq!(time.offset().unwrap(), "+01:00");
+
+        // Falls back to the repr when the timestamp is missing or can't be used
+        for timestamp in [None, Some(f64::NAN), Some(1e20)] {
+            let time = Timestamp {
+                timestamp,
+                ..time.clone()
+            };
+            assert_eq!(time.as_rfc3339().unwrap(), "2024-03-05T12:00:00.123456Z");
+        }
+
+        let time = Timestamp {
+            repr: "yesterday".into(),
+            timestamp: Some(f64::INFINITY),
+            ..Default::default()
+        };
+        assert_eq!(time.as_rfc3339(), None);
+        assert_eq!(time.offset(), None);
+    }
+
+    /// Msgpack for a BEC LogMessage carrying the given log_msg, as BEC would publish it
+    fn log_message_bytes(log_msg: &LogMsg) -> Vec<u8> {
+        let message = serde_json::json!({ "__bec_codec__": {
+            "encoder_name": "BECMessageEncoder",
+            "type_name": LOG_MESSAGE,
+            "data": { "log_type": "", "log_msg": log_msg, "metadata": {} },
+        }});
+        rmp_serde::to_vec_named(&message).unwrap()
+    }
+
+    #[test]
+    fn test_error_log_item_contents() {
+        let err_item = error_log_item();
+        assert_eq!(err_item.record.level.name, "ERROR");
         assert_eq!(
-            unpacked[0].bec_codec.data.log_msg.record.level.name,
-            "ERROR"
+            err_item.record.message,
+            "Error processing log messages from Redis!"
         );
     }
 
     #[test]
-    fn test_process_data_invalid_type() {
+    fn test_decode_entry_valid() {
+        let bytes = log_message_bytes(&error_log_item());
+        let redis_val = redis::Value::BulkString(bytes);
+        let result = decode_entry(Decoder::BecLog, &redis_val);
+        assert!(result.is_ok());
+        let Payload::Log(msg) = result.unwrap() else {
+            panic!("Expected a log message")
+        };
+        assert_eq!(msg.record.level.name, "ERROR");
+    }
+
+    #[test]
+    fn test_decode_entry_invalid_type() {
         let redis_val = redis::Value::Int(42);
-        let result = process_data(vec![redis_val]);
+        let result = decode_entry(Decoder::BecLog, &redis_val);
         assert!(result.is_err());
     }
 
     #[test]
-    fn test_extract_records_empty() {
-        let records = extract_records(vec![]);
-        assert!(records.is_empty());
+    fn test_decode_entries_independently() {
+        let mut msg = error_log_item();
+        msg.record.message = "test".to_string();
+        let good = redis::Value::BulkString(log_message_bytes(&msg));
+        let bad = redis::Value::BulkString(vec![0xc1, 0x00, 0xff]);
+        let results: Vec<_> = [good.clone(), bad, good]
+            .iter()
+            .map(|v| decode_entry(Decoder::BecLog, v))
+            .collect();
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+        let Ok(Payload::Log(msg)) = &results[2] else {
+            panic!("Expected a log message")
+        };
+        assert_eq!(msg.record.message, "test");
     }
 
     #[test]
     fn test_logrecord_serde_roundtrip() {
-        let record = error_log_item().bec_codec.data.log_msg.record.clone();
+        let record = error_log_item().record;
         let ser = serde_json::to_string(&record).unwrap();
         let de: LogRecord = serde_json::from_str(&ser).unwrap();
         assert_eq!(record, de);
     }
+
+    #[test]
+    fn test_split_entries_missing_data() {
+        let bytes = log_message_bytes(&error_log_item());
+        let entries = vec![
+            redis::streams::StreamId {
+                id: "1-0".into(),
+                map: [("data".to_string(), redis::Value::BulkString(bytes.clone()))].into(),
+            },
+            redis::streams::StreamId {
+                id: "2-0".into(),
+                map: Default::default(),
+            },
+        ];
+        let (ids, values) = split_entries(&entries);
+        assert_eq!(ids, vec!["1-0", "2-0"]);
+        assert_eq!(values[0], redis::Value::BulkString(bytes));
+        assert_eq!(values[1], redis::Value::Nil);
+    }
+
+    #[test]
+    fn test_decode_entry_msgpack() {
+        let value = serde_json::json!({ "status": "running", "progress": 0.5 });
+        let bytes = rmp_serde::to_vec_named(&value).unwrap();
+        let payload = decode_entry(Decoder::Msgpack, &redis::Value::BulkString(bytes)).unwrap();
+        assert_eq!(payload, Payload::Value(value));
+    }
+
+    #[test]
+    fn test_decode_entry_bec_message() {
+        let status = serde_json::json!({ "__bec_codec__": {
+            "encoder_name": "BECMessageEncoder",
+            "type_name": "ScanStatusMessage",
+            "data": { "scan_id": "abc", "status": { "__bec_codec__": {
+                "encoder_name": "EnumEncoder",
+                "type_name": "ScanStatus",
+                "data": "open",
+            }}},
+        }});
+        let bytes = redis::Value::BulkString(rmp_serde::to_vec_named(&status).unwrap());
+        let payload = decode_entry(Decoder::BecMessage, &bytes).unwrap();
+        assert_eq!(
+            payload,
+            Payload::Value(serde_json::json!({
+                "scan_id": "abc",
+                "status": "open",
+                "bec_message_type": "ScanStatusMessage",
+            }))
+        );
+        let error = decode_entry(Decoder::BecLog, &bytes).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Expected a LogMessage, got ScanStatusMessage"
+        );
+
+        let log = log_message_bytes(&error_log_item());
+        let payload = decode_entry(Decoder::BecMessage, &redis::Value::BulkString(log)).unwrap();
+        assert!(matches!(payload, Payload::Log(_)));
+    }
+
+    #[test]
+    fn test_decode_entry_malformed_log_message() {
+        for data in [
+            serde_json::json!(["log_msg"]),
+            serde_json::json!("log_msg"),
+            serde_json::json!({ "log_type": "info" }),
+        ] {
+            let message = serde_json::json!({ "__bec_codec__": {
+                "encoder_name": "BECMessageEncoder",
+                "type_name": "LogMessage",
+                "data": data,
+            }});
+            let bytes = redis::Value::BulkString(rmp_serde::to_vec_named(&message).unwrap());
+            assert!(decode_entry(Decoder::BecLog, &bytes).is_err());
+            assert!(decode_entry(Decoder::BecMessage, &bytes).is_err());
+        }
+    }
+
+    /// Decode one of the synthetic LogMessage fixtures, which model the layouts different BEC
+    /// releases send (see fixtures/README.md)
+    fn decode_fixture(bytes: &[u8]) -> LogMsg {
+        let value = redis::Value::BulkString(bytes.to_vec());
+        let Payload::Log(msg) = decode_entry(Decoder::BecLog, &value).unwrap() else {
+            panic!("Expected a log message")
+        };
+        *msg
+    }
+
+    #[test]
+    fn test_decode_current() {
+        let msg = decode_fixture(include_bytes!("fixtures/log_message_current.msgpack"));
+        assert_eq!(msg.schema_version, RECORD_SCHEMA);
+        assert_eq!(msg.service_name, "ScanServer");
+        assert_eq!(msg.record.level.name, "INFO");
+        assert_eq!(msg.record.process.id, 4242);
+        assert_eq!(msg.record.elapsed.seconds, 5.0001);
+        assert_eq!(
+            msg.record.time.as_rfc3339().unwrap(),
+            "2024-03-05T12:00:00.123456Z"
+        );
+        assert!(msg.other.is_empty());
+        assert!(msg.record.other.is_empty());
+    }
+
+    #[test]
+    fn test_decode_text() {
+        let msg = decode_fixture(include_bytes!("fixtures/log_message_text.msgpack"));
+        assert_eq!(msg.schema_version, TEXT_SCHEMA);
+        assert_eq!(msg.record.level.name, "ERROR");
+        assert_eq!(
+            msg.record.message,
+            "2024-03-05 13:00:00 | ScanServer | ERROR | Scan 12 failed"
+        );
+        assert!(msg.text.ends_with('\n'));
+        assert_eq!(msg.record.time.utc(), None);
+    }
+
+    #[test]
+    fn test_decode_partial() {
+        let msg = decode_fixture(include_bytes!("fixtures/log_message_partial.msgpack"));
+        assert_eq!(msg.schema_version, RECORD_SCHEMA);
+        assert_eq!(msg.record.message, "Scan 12 started");
+        assert_eq!(msg.record.thread, NameId::default());
+        assert_eq!(msg.record.file, File::default());
+        assert_eq!(msg.text, "");
+        assert_eq!(
+            msg.record.time.as_rfc3339().unwrap(),
+            "2024-03-05T12:00:00.123456Z"
+        );
+        assert_eq!(msg.record.time.offset(), None);
+    }
+
+    #[test]
+    fn test_decode_extended() {
+        let msg = decode_fixture(include_bytes!("fixtures/log_message_extended.msgpack"));
+        assert_eq!(msg.schema_version, RECORD_SCHEMA);
+        assert_eq!(msg.record.message, "Scan 12 started");
+        assert_eq!(msg.other["hostname"], "beamline-x");
+        assert_eq!(msg.record.other["context"]["scan_id"], "d1f2");
+        assert_eq!(msg.record.level.other["color"], "<bold>");
+        assert_eq!(msg.record.extra["status"], "open");
+        // Unknown fields go back where they came from
+        let record = serde_json::to_value(&msg.record).unwrap();
+        assert_eq!(record["level"]["color"], "<bold>");
+    }
+
+    #[test]
+    fn test_decode_error_msg() {
+        let entry = EntryId {
+            stream: "info/log".into(),
+            id: "1722872581000-3".into(),
+        };
+        let msg = decode_error_msg(&entry, "invalid type");
+        assert_eq!(msg.record.level.name, "ERROR");
+        assert_eq!(
+            msg.record.message,
+            "Failed to decode entry 1722872581000-3 from info/log: invalid type"
+        );
+        assert_eq!(msg.record.extra["stream_id"], "1722872581000-3");
+        assert!(msg.record.time.timestamp.unwrap() > 0.0);
+    }
+
+    #[test]
+    fn test_in_flight() {
+        let entry = |stream: &str, id: &str| EntryId {
+            stream: stream.into(),
+            id: id.into(),
+        };
+        let in_flight = InFlight::default();
+        in_flight.insert(entry("info/log", "1-0"));
+        in_flight.insert(entry("info/log", "2-0"));
+        in_flight.insert(entry("scans", "1-0"));
+        assert!(in_flight.contains(&entry("scans", "1-0")));
+        assert!(!in_flight.contains(&entry("scans", "2-0")));
+        let mut ids = in_flight.ids("info/log");
+        ids.sort();
+        assert_eq!(ids, vec!["1-0", "2-0"]);
+        in_flight.release(&[entry("info/log", "1-0"), entry("scans", "1-0")]);
+        assert_eq!(in_flight.ids("info/log"), vec!["2-0"]);
+        assert!(in_flight.ids("scans").is_empty());
+    }
+
+    #[test]
+    fn test_refresh_interval() {
+        let config: RedisConfig = toml::from_str(
+            "claim_min_idle_millis = 60000\n[url]\nurl = \"redis://localhost\"\nport = 6379",
+        )
+        .unwrap();
+        assert_eq!(refresh_interval(&config), Duration::from_secs(20));
+    }
+
+    #[test]
+    fn test_needs_reconnect() {
+        let dropped: redis::RedisError =
+            std::io::Error::from(std::io::ErrorKind::ConnectionReset).into();
+        assert!(needs_reconnect(&dropped));
+        let nogroup = redis::make_extension_error(
+            "NOGROUP".into(),
+            Some("No such key or consumer group".into()),
+        );
+        assert!(needs_reconnect(&nogroup));
+        assert!(!needs_reconnect(str_error(NOT_BINARY).as_ref()));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_capacity() {
+        let (tx, mut rx) = mpsc::channel(3);
+        let msg = |id: &str| StreamMsg {
+            entry: EntryId {
+                stream: "info/log".into(),
+                id: id.into(),
+            },
+            index: None,
+            payload: Payload::Value(serde_json::Value::Null),
+        };
+        tx.send(msg("0-1")).await.unwrap();
+        tx.send(msg("0-2")).await.unwrap();
+        // More than the queue can hold is capped at its capacity
+        let waiting = tokio::time::timeout(Duration::from_millis(20), wait_for_capacity(&tx, 10));
+        assert!(waiting.await.is_err());
+        rx.recv().await.unwrap();
+        assert!(wait_for_capacity(&tx, 2).await);
+        rx.recv().await.unwrap();
+        assert!(wait_for_capacity(&tx, 10).await);
+        drop(rx);
+        assert!(!wait_for_capacity(&tx, 10).await);
+    }
+
+    #[test]
+    fn test_is_stale() {
+        let config: RedisConfig = toml::from_str(
+            "
+url = { url = \"redis://localhost\", port = 6379 }
+consumer_id = \"me\"
+stale_consumer_idle_millis = 1000
+",
+        )
+        .unwrap();
+        let consumer = |name: &str, pending, idle| redis::streams::StreamInfoConsumer {
+            name: name.into(),
+            pending,
+            idle,
+        };
+        assert!(is_stale(&consumer("other", 0, 1000), &config));
+        assert!(!is_stale(&consumer("me", 0, 1000), &config));
+        assert!(!is_stale(&consumer("other", 1, 1000), &config));
+        assert!(!is_stale(&consumer("other", 0, 999), &config));
+    }
 }
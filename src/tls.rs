@@ -0,0 +1,499 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::{TcpStream, ToSocketAddrs},
+    sync::Mutex,
+    time::Duration,
+};
+
+use elasticsearch::{
+    auth::ClientCertificate,
+    cert::{Certificate, CertificateValidation},
+    http::Url,
+};
+use openssl::{
+    hash::MessageDigest,
+    pkcs12::Pkcs12,
+    pkey::PKey,
+    ssl::{SslConnector, SslMethod, SslVerifyMode},
+    stack::Stack,
+    x509::{X509, X509Ref},
+};
+
+use crate::config::TlsConfig;
+
+const FINGERPRINT_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn str_error(msg: String) -> Box<dyn Error> {
+    msg.into()
+}
+
+/// Normalise a SHA-256 fingerprint as printed by e.g. `openssl x509 -fingerprint`, or by
+/// Elasticsearch on first startup, to lowercase hex without separators
+fn normalise_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn fingerprint(cert: &X509Ref) -> Result<String, Box<dyn Error>> {
+    let digest = cert.digest(MessageDigest::sha256())?;
+    Ok(digest.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Connect to the server and find the certificate in the chain it presents which has the given
+/// fingerprint. The chain isn't verified here; the certificate found is trusted as a CA for the
+/// actual connections, which are then fully verified against it.
+fn certificate_by_fingerprint(url: &Url, wanted: &str) -> Result<X509, Box<dyn Error>> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| str_error(format!("No host in {url}")))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| str_error(format!("No port in {url}")))?;
+    let addr = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| str_error(format!("Can't resolve {host}:{port}")))?;
+    let stream = TcpStream::connect_timeout(&addr, FINGERPRINT_TIMEOUT)?;
+    stream.set_read_timeout(Some(FINGERPRINT_TIMEOUT))?;
+
+    let mut connector = SslConnector::builder(SslMethod::tls())?;
+    connector.set_verify(SslVerifyMode::NONE);
+    let tls = connector
+        .build()
+        .connect(host, stream)
+        .map_err(|e| str_error(format!("TLS handshake with {host}:{port} failed: {e}")))?;
+    let chain = tls
+        .ssl()
+        .peer_cert_chain()
+        .ok_or_else(|| str_error(format!("{host}:{port} presented no certificates")))?;
+
+    let wanted = normalise_fingerprint(wanted);
+    for cert in chain {
+        if fingerprint(cert)? == wanted {
+            return Ok(cert.to_owned());
+        }
+    }
+    Err(str_error(format!(
+        "No certificate presented by {host}:{port} matches extra_ca_fingerprint"
+    )))
+}
+
+/// Certificates found by their fingerprint, by the host and port which presented them, so that
+/// each server is only asked once
+#[derive(Default)]
+pub struct FingerprintCerts(Mutex<HashMap<String, X509>>);
+
+impl FingerprintCerts {
+    /// The certificate the server at `url` presents with the given fingerprint. Fetching it means
+    /// a blocking connection and handshake, so that is done off the async runtime.
+    async fn get(&self, url: &Url, wanted: &str) -> Result<X509, String> {
+        let key = url.origin().ascii_serialization();
+        if let Some(cert) = self.0.lock().unwrap().get(&key) {
+            return Ok(cert.clone());
+        }
+        let (url, wanted) = (url.clone(), wanted.to_owned());
+        let cert = tokio::task::spawn_blocking(move || {
+            certificate_by_fingerprint(&url, &wanted).map_err(|e| e.to_string())
+        })
+        .await
+        .map_err(|e| e.to_string())??;
+        self.0.lock().unwrap().insert(key, cert.clone());
+        Ok(cert)
+    }
+}
+
+/// How to validate the certificate presented by Elastic, according to the TLS config. The
+/// certificate found by `extra_ca_fingerprint` is fetched once, when the node's client is set up,
+/// and is then trusted as a CA on top of the system's ones. Later handshakes are verified as
+/// usual and don't check the fingerprint again, so this isn't pinning.
+pub async fn cert_validation(
+    config: &TlsConfig,
+    url: &Url,
+    fingerprint_certs: &FingerprintCerts,
+) -> Result<CertificateValidation, Box<dyn Error>> {
+    let options = [
+        config.ca_file.is_some(),
+        config.extra_ca_fingerprint.is_some(),
+        config.insecure_skip_verify,
+    ];
+    if options.iter().filter(|set| **set).count() > 1 {
+        return Err(str_error(
+            "Only one of ca_file, extra_ca_fingerprint and insecure_skip_verify may be set".into(),
+        ));
+    }
+
+    if let Some(path) = &config.ca_file {
+        let pem = std::fs::read(path)
+            .map_err(|e| str_error(format!("Can't read CA file {}: {e}", path.display())))?;
+        return Ok(CertificateValidation::Full(Certificate::from_pem(&pem)?));
+    }
+    if let Some(wanted) = &config.extra_ca_fingerprint {
+        let cert = fingerprint_certs.get(url, wanted).await?;
+        return Ok(CertificateValidation::Full(Certificate::from_der(
+            &cert.to_der()?,
+        )?));
+    }
+    if config.insecure_skip_verify {
+        println!("WARNING: ******************************************************************");
+        println!("WARNING: TLS certificate verification for Elastic is DISABLED!");
+        println!("WARNING: Credentials will be sent to whoever answers at {url}");
+        println!("WARNING: ******************************************************************");
+        return Ok(CertificateValidation::None);
+    }
+    Ok(CertificateValidation::Default)
+}
+
+fn read_file(path: &std::path::Path, what: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    std::fs::read(path).map_err(|e| str_error(format!("Can't read {what} {}: {e}", path.display())))
+}
+
+/// Bundle a PEM client certificate (followed by any intermediates) and its key as PKCS#12, which
+/// is the form the Elastic client takes them in with native TLS
+fn pkcs12_from_pem(cert_pem: &[u8], key_pem: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut certs = X509::stack_from_pem(cert_pem)?.into_iter();
+    let cert = certs
+        .next()
+        .ok_or_else(|| str_error("No certificate in client certificate file".into()))?;
+    let mut chain = Stack::new()?;
+    for intermediate in certs {
+        chain.push(intermediate)?;
+    }
+    let key = PKey::private_key_from_pem(key_pem)?;
+    let pkcs12 = Pkcs12::builder()
+        .name("bec_log_ingestor")
+        .pkey(&key)
+        .cert(&cert)
+        .ca(chain)
+        .build2("")?;
+    Ok(pkcs12.to_der()?)
+}
+
+/// The client certificate to authenticate to Elastic with, if one is configured. The key is read
+/// from the certificate file if no separate key file is given.
+pub fn client_certificate(config: &TlsConfig) -> Result<Option<ClientCertificate>, Box<dyn Error>> {
+    match (&config.client_cert, &config.client_pkcs12) {
+        (Some(_), Some(_)) => Err(str_error(
+            "Only one of client_cert and client_pkcs12 may be set".into(),
+        )),
+        (Some(cert_path), None) => {
+            let cert = read_file(cert_path, "client certificate")?;
+            let key = match &config.client_key {
+                Some(key_path) => read_file(key_path, "client key")?,
+                None => cert.clone(),
+            };
+            Ok(Some(ClientCertificate::Pkcs12(
+                pkcs12_from_pem(&cert, &key)?,
+                None,
+            )))
+        }
+        (None, Some(path)) => Ok(Some(ClientCertificate::Pkcs12(
+            read_file(path, "client PKCS#12 archive")?,
+            config
+                .client_pkcs12_password
+                .as_ref()
+                .map(|p| p.expose().to_owned()),
+        ))),
+        (None, None) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        path::PathBuf,
+    };
+
+    use elasticsearch::{
+        Elasticsearch,
+        http::transport::{SingleNodeConnectionPool, TransportBuilder},
+    };
+    use openssl::{
+        asn1::Asn1Time,
+        bn::BigNum,
+        ec::{EcGroup, EcKey},
+        nid::Nid,
+        pkey::{PKey, Private},
+        ssl::SslAcceptor,
+        x509::{
+            X509Builder, X509NameBuilder,
+            extension::{BasicConstraints, SubjectAlternativeName},
+        },
+    };
+
+    pub(crate) fn self_signed() -> (X509, PKey<Private>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "localhost")
+            .unwrap();
+        let name = name.build();
+
+        let mut cert = X509Builder::new().unwrap();
+        cert.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        cert.set_serial_number(&serial).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns("localhost")
+            .build(&cert.x509v3_context(None, None))
+            .unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        (cert.build(), key)
+    }
+
+    pub(crate) fn test_server() -> (Url, X509) {
+        test_server_with_client_ca(None)
+    }
+
+    /// A local HTTPS server with a self-signed certificate, answering every request with an empty
+    /// JSON object. If given a client CA, it requires clients to present a certificate signed by
+    /// it. Returns its URL and certificate.
+    fn test_server_with_client_ca(client_ca: Option<&X509>) -> (Url, X509) {
+        let (cert, key) = self_signed();
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        if let Some(ca) = client_ca {
+            acceptor.cert_store_mut().add_cert(ca.clone()).unwrap();
+            acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        }
+        let acceptor = acceptor.build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // Clients which reject the certificate fail the handshake
+                let Ok(mut tls) = acceptor.accept(stream) else {
+                    continue;
+                };
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match tls.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let _ = tls.write_all(
+                    b"HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: 2\r\nconnection: close\r\n\r\n{}",
+                );
+                let _ = tls.shutdown();
+            }
+        });
+        let url = Url::parse(&format!("https://localhost:{port}")).unwrap();
+        (url, cert)
+    }
+
+    async fn ping(url: &Url, validation: CertificateValidation) -> bool {
+        ping_as(url, validation, None).await
+    }
+
+    async fn ping_as(
+        url: &Url,
+        validation: CertificateValidation,
+        client_cert: Option<ClientCertificate>,
+    ) -> bool {
+        let mut transport = TransportBuilder::new(SingleNodeConnectionPool::new(url.clone()))
+            .cert_validation(validation);
+        if let Some(cert) = client_cert {
+            transport = transport.auth(elasticsearch::auth::Credentials::Certificate(cert));
+        }
+        let client = Elasticsearch::new(transport.build().unwrap());
+        client.ping().send().await.is_ok()
+    }
+
+    pub(crate) fn write_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("bec-ingestor-{}-{name}", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn write_pem(cert: &X509) -> PathBuf {
+        write_file("ca.pem", &cert.to_pem().unwrap())
+    }
+
+    fn trust(cert: &X509) -> CertificateValidation {
+        CertificateValidation::Full(Certificate::from_der(&cert.to_der().unwrap()).unwrap())
+    }
+
+    #[test]
+    fn test_normalise_fingerprint() {
+        assert_eq!(normalise_fingerprint("AB:cd:0F"), "abcd0f");
+        assert_eq!(normalise_fingerprint(" abcd 0f\n"), "abcd0f");
+    }
+
+    #[tokio::test]
+    async fn test_conflicting_options() {
+        let config = TlsConfig {
+            ca_file: Some("ca.pem".into()),
+            insecure_skip_verify: true,
+            ..Default::default()
+        };
+        let url = Url::parse("https://localhost:9200").unwrap();
+        assert!(
+            cert_validation(&config, &url, &FingerprintCerts::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_default_rejects_self_signed() {
+        let (url, _) = test_server();
+        let validation = cert_validation(&TlsConfig::default(), &url, &FingerprintCerts::default())
+            .await
+            .unwrap();
+        assert!(!ping(&url, validation).await);
+    }
+
+    #[tokio::test]
+    async fn test_ca_file() {
+        let (url, cert) = test_server();
+        let path = write_pem(&cert);
+        let config = TlsConfig {
+            ca_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let validation = cert_validation(&config, &url, &FingerprintCerts::default())
+            .await
+            .unwrap();
+        assert!(ping(&url, validation).await);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ca_file_missing() {
+        let config = TlsConfig {
+            ca_file: Some("/nonexistent/ca.pem".into()),
+            ..Default::default()
+        };
+        let url = Url::parse("https://localhost:9200").unwrap();
+        assert!(
+            cert_validation(&config, &url, &FingerprintCerts::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_extra_ca_fingerprint() {
+        let (url, cert) = test_server();
+        let wanted = fingerprint(&cert).unwrap().to_uppercase();
+        let config = TlsConfig {
+            extra_ca_fingerprint: Some(wanted),
+            ..Default::default()
+        };
+        let fingerprint_certs = FingerprintCerts::default();
+        let validation = cert_validation(&config, &url, &fingerprint_certs)
+            .await
+            .unwrap();
+        assert!(ping(&url, validation).await);
+        // The certificate is only fetched once per server
+        assert_eq!(fingerprint_certs.0.lock().unwrap().len(), 1);
+        let other = Url::parse(&format!("{url}_bulk")).unwrap();
+        assert!(
+            cert_validation(&config, &other, &fingerprint_certs)
+                .await
+                .is_ok()
+        );
+        assert_eq!(fingerprint_certs.0.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_extra_ca_fingerprint_mismatch() {
+        let (url, _) = test_server();
+        let (other, _) = self_signed();
+        let config = TlsConfig {
+            extra_ca_fingerprint: Some(fingerprint(&other).unwrap()),
+            ..Default::default()
+        };
+        let Err(error) = cert_validation(&config, &url, &FingerprintCerts::default()).await else {
+            panic!("Certificate with the wrong fingerprint was accepted");
+        };
+        assert!(error.to_string().contains("matches extra_ca_fingerprint"));
+    }
+
+    #[tokio::test]
+    async fn test_insecure_skip_verify() {
+        let (url, _) = test_server();
+        let config = TlsConfig {
+            insecure_skip_verify: true,
+            ..Default::default()
+        };
+        let validation = cert_validation(&config, &url, &FingerprintCerts::default())
+            .await
+            .unwrap();
+        assert!(ping(&url, validation).await);
+    }
+
+    #[tokio::test]
+    async fn test_client_cert_pem() {
+        let (client_cert, client_key) = self_signed();
+        let (url, server_cert) = test_server_with_client_ca(Some(&client_cert));
+        assert!(!ping(&url, trust(&server_cert)).await);
+
+        let cert_path = write_file("client.pem", &client_cert.to_pem().unwrap());
+        let key_path = write_file(
+            "client.key",
+            &client_key.private_key_to_pem_pkcs8().unwrap(),
+        );
+        let config = TlsConfig {
+            client_cert: Some(cert_path.clone()),
+            client_key: Some(key_path.clone()),
+            ..Default::default()
+        };
+        let identity = client_certificate(&config).unwrap();
+        assert!(ping_as(&url, trust(&server_cert), identity).await);
+        std::fs::remove_file(cert_path).unwrap();
+        std::fs::remove_file(key_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_cert_pkcs12() {
+        let (client_cert, client_key) = self_signed();
+        let (url, server_cert) = test_server_with_client_ca(Some(&client_cert));
+        let pkcs12 = Pkcs12::builder()
+            .pkey(&client_key)
+            .cert(&client_cert)
+            .build2("secret")
+            .unwrap();
+        let path = write_file("client.p12", &pkcs12.to_der().unwrap());
+        let config = TlsConfig {
+            client_pkcs12: Some(path.clone()),
+            client_pkcs12_password: Some("secret".into()),
+            ..Default::default()
+        };
+        let identity = client_certificate(&config).unwrap();
+        assert!(ping_as(&url, trust(&server_cert), identity).await);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_client_cert_none_or_conflicting() {
+        assert!(client_certificate(&TlsConfig::default()).unwrap().is_none());
+        let config = TlsConfig {
+            client_cert: Some("client.pem".into()),
+            client_pkcs12: Some("client.p12".into()),
+            ..Default::default()
+        };
+        assert!(client_certificate(&config).is_err());
+    }
+}